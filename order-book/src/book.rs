@@ -0,0 +1,3195 @@
+use std::collections::btree_map::Entry;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::ops::{Bound, RangeBounds};
+
+use crate::clock::{Clock, SystemClock};
+use crate::command::Command;
+use crate::counted::CountedTree;
+use crate::error::{Error, Reject, Result};
+use crate::policy::{Fifo, MatchingPolicy};
+
+mod persist;
+
+/// Handed out by `OrderBook::add`, stays with the order until it leaves the book.
+pub type OrderId = u64;
+
+/// The account or desk behind an order, orders with the same owner never
+/// trade with each other.
+pub type OwnerId = u64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Order {
+    pub id: OrderId,
+    /// Shown at the level, all of it unless the order is an iceberg
+    pub amount: u64,
+    // Grows with every order that joins a level, so a level is always sorted by it
+    seq: u64,
+    // Iceberg reserve, shown `peak` at a time once `amount` is used up
+    hidden: u64,
+    peak: Option<u64>,
+    peg: Option<Peg>,
+    owner: Option<OwnerId>,
+    all_or_none: bool,
+}
+
+impl Order {
+    /// Iceberg reserve not yet shown at the level.
+    pub fn hidden(&self) -> u64 {
+        self.hidden
+    }
+
+    pub fn owner(&self) -> Option<OwnerId> {
+        self.owner
+    }
+
+    /// Only ever trades its whole amount at once, sweeps too small for it
+    /// pass it over.
+    pub fn all_or_none(&self) -> bool {
+        self.all_or_none
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Side {
+    Buy,
+    Sell,
+}
+
+impl Side {
+    pub fn opposite(self) -> Side {
+        match self {
+            Side::Buy => Side::Sell,
+            Side::Sell => Side::Buy,
+        }
+    }
+}
+
+/// How long an order stays on the book once it has traded what it can.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TimeInForce {
+    /// Rest whatever does not trade straight away
+    #[default]
+    GoodTillCancel,
+    /// Trade what crosses, drop the rest
+    ImmediateOrCancel,
+    /// Trade the whole amount straight away or be rejected
+    FillOrKill,
+    /// Rest the whole amount without trading, rejected if it would cross
+    PostOnly,
+    /// Rest until the book's clock reaches this time
+    GoodTillTime(u64),
+    /// Rest until the book's sequence number reaches this
+    GoodTillSequence(u64),
+}
+
+/// What the book does when an incoming order would trade with a resting
+/// order of the same owner. Neither order trades with the other either way.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SelfTradePrevention {
+    /// Drop the resting order and carry on matching
+    CancelResting,
+    /// Drop what is left of the incoming order
+    #[default]
+    CancelIncoming,
+    /// Drop the resting order and what is left of the incoming order
+    CancelBoth,
+    /// Take the smaller of the two amounts off both and carry on matching
+    DecrementBoth,
+}
+
+/// The price a pegged order follows.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum PegReference {
+    BestBid,
+    BestAsk,
+    /// Halfway between the best bid and ask, rounded down for a buy and up
+    /// for a sell so it never ends up the more aggressive side of the middle
+    Midpoint,
+}
+
+/// Prices an order at its reference plus `offset`.
+///
+/// References only look at orders with a price of their own, so pegged orders
+/// never chase each other.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Peg {
+    pub reference: PegReference,
+    pub offset: i64,
+}
+
+/// A limit order on its way into `OrderBook::submit`.
+///
+/// `NewOrder::limit` gives a good-till-cancel order, the other fields are set
+/// with struct update syntax.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NewOrder {
+    pub side: Side,
+    pub price: u64,
+    pub amount: u64,
+    pub time_in_force: TimeInForce,
+    /// Makes an iceberg: only this much shows at the level at a time and the
+    /// rest refills it from a hidden reserve
+    pub peak: Option<u64>,
+    /// Makes the order follow a reference price, `price` is worked out from
+    /// it on submission and whenever the reference moves
+    pub peg: Option<Peg>,
+    pub owner: Option<OwnerId>,
+    /// Trade at least this much on arrival or be rejected, the order rests
+    /// without it afterwards
+    pub min_quantity: Option<u64>,
+    /// Trade the whole amount in one go. An order the book cannot fill in
+    /// full on arrival trades nothing: it rests whole unless immediate-or-cancel
+    /// drops it, and only ever trades in full from then on.
+    pub all_or_none: bool,
+}
+
+impl NewOrder {
+    pub fn limit(side: Side, price: u64, amount: u64) -> Self {
+        Self {
+            side,
+            price,
+            amount,
+            time_in_force: TimeInForce::GoodTillCancel,
+            peak: None,
+            peg: None,
+            owner: None,
+            min_quantity: None,
+            all_or_none: false,
+        }
+    }
+
+    pub fn pegged(side: Side, peg: Peg, amount: u64) -> Self {
+        Self {
+            peg: Some(peg),
+            ..Self::limit(side, 0, amount)
+        }
+    }
+}
+
+/// An order held off the book until a trade at or through `trigger`: at or
+/// above it for a buy, at or below it for a sell.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Stop {
+    pub side: Side,
+    pub trigger: u64,
+    pub amount: u64,
+    /// Goes in as a good-till-cancel limit order at this price once
+    /// triggered, as a market order without one
+    pub limit: Option<u64>,
+    pub owner: Option<OwnerId>,
+}
+
+/// What became of a limit order handed to `OrderBook::add`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Added {
+    pub id: OrderId,
+    /// Matched straight away against the opposite side
+    pub filled: u64,
+    pub cost: u128,
+    /// Left resting on the book under `id`, hidden reserve included
+    pub resting: u64,
+    /// Dropped instead of resting, by immediate-or-cancel or by self-trade
+    /// prevention
+    pub cancelled: u64,
+}
+
+/// What to do when the book cannot cover the whole amount of a take.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TakeMode {
+    /// Fill what is there and hand back the rest
+    Partial,
+    /// Fill everything, or fail with `Error::Rejected` and fill nothing
+    FillOrKill,
+}
+
+/// Outcome of `OrderBook::take`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Taken {
+    pub filled: u64,
+    pub cost: u128,
+    /// Asked for but not filled, for lack of liquidity or by self-trade
+    /// prevention
+    pub unfilled: u64,
+}
+
+/// Outcome of `OrderBook::take_notional`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Spent {
+    pub filled: u64,
+    /// Never above the budget, the difference is what one more unit would
+    /// not have fitted into
+    pub cost: u128,
+}
+
+/// What a take would cost right now, worked out without touching the book.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Quote {
+    /// Below the asked amount when the book runs out
+    pub filled: u64,
+    pub cost: u128,
+    /// Last price the take would reach, `None` if nothing fills
+    pub worst_price: Option<u64>,
+    /// Number of price levels the take would trade at
+    pub levels: usize,
+}
+
+impl Quote {
+    /// Cost per unit over the whole quote.
+    pub fn average_price(&self) -> Option<f64> {
+        (self.filled > 0).then(|| self.cost as f64 / self.filled as f64)
+    }
+}
+
+/// One trade between an incoming order and a resting (maker) order.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Fill {
+    pub maker_id: OrderId,
+    pub price: u64,
+    pub amount: u64,
+    /// What is left of the maker order, hidden reserve included, 0 once it is
+    /// fully consumed and off the book
+    pub maker_remaining: u64,
+}
+
+impl Fill {
+    pub fn is_maker_done(&self) -> bool {
+        self.maker_remaining == 0
+    }
+}
+
+/// Receives a `Fill` for every resting order an incoming order trades with,
+/// in the order the trades happen.
+pub trait FillSink {
+    fn fill(&mut self, fill: Fill);
+}
+
+impl FillSink for Vec<Fill> {
+    fn fill(&mut self, fill: Fill) {
+        self.push(fill);
+    }
+}
+
+// Drops every fill, for callers that only want the totals
+impl FillSink for () {
+    fn fill(&mut self, _fill: Fill) {}
+}
+
+/// One change to the book, as a `BookListener` hears of it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BookEvent {
+    /// An order came to rest, `amount` counting any iceberg reserve
+    OrderAdded { id: OrderId, side: Side, price: u64, amount: u64 },
+    /// A resting order left the book without trading the `amount` it had
+    /// left. Cancels, expiry and self-trade prevention take orders off this
+    /// way, and so do amends and pegs moving an order, which then comes back
+    /// with an `OrderAdded` if it rests again.
+    OrderCancelled { id: OrderId, side: Side, price: u64, amount: u64 },
+    /// A resting order was cut down to `amount` where it stands, by an amend
+    /// or by self-trade prevention
+    OrderReduced { id: OrderId, side: Side, price: u64, amount: u64 },
+    /// A resting order on `side` traded
+    OrderFilled { side: Side, fill: Fill },
+    LevelCreated { side: Side, price: u64 },
+    LevelRemoved { side: Side, price: u64 },
+    /// The best price on `side` or the quantity shown there moved over the
+    /// call, reported once at its end. `price` is `None` once the side is
+    /// empty.
+    TopOfBookChanged { side: Side, price: Option<u64>, quantity: u128 },
+}
+
+/// Hears of every change to the book, in the order the changes happen.
+///
+/// A listener only hears of the calls it is handed to, the `_into` version
+/// of every change, `apply` included. Events are small `Copy` values handed
+/// over one at a time, so nothing gets allocated for them. Every `FillSink`
+/// listens for fills and ignores the rest.
+pub trait BookListener {
+    fn on_event(&mut self, event: BookEvent);
+}
+
+impl<T: FillSink> BookListener for T {
+    fn on_event(&mut self, event: BookEvent) {
+        if let BookEvent::OrderFilled { fill, .. } = event {
+            self.fill(fill);
+        }
+    }
+}
+
+/// Every order resting at one price, in time priority, with running totals.
+///
+/// The totals are kept wide enough that no number of `u64` orders can
+/// overflow them, the way costs are.
+#[derive(Debug, Clone, Default)]
+pub struct Level {
+    orders: VecDeque<Order>,
+    quantity: u128,
+    hidden: u128,
+    pegged_count: usize,
+    all_or_none_count: usize,
+}
+
+impl Level {
+    /// Sum of the shown amounts of every order at this price, iceberg
+    /// reserves left out.
+    pub fn quantity(&self) -> u128 {
+        self.quantity
+    }
+
+    pub fn order_count(&self) -> usize {
+        self.orders.len()
+    }
+
+    /// Orders at this price in time priority.
+    pub fn orders(&self) -> impl ExactSizeIterator<Item = &Order> + DoubleEndedIterator {
+        self.orders.iter()
+    }
+
+    // Everything a sweep could take here, iceberg reserves included
+    fn total(&self) -> u128 {
+        self.quantity + self.hidden
+    }
+
+    // How much of `amount` a sweep could take here, in time priority and
+    // passing over all-or-none orders too big for what is left of it, and
+    // the orders of `owner` if there is one
+    fn fillable(&self, amount: u64, owner: Option<OwnerId>) -> u64 {
+        if self.all_or_none_count == 0 && owner.is_none() {
+            return self.total().min(u128::from(amount)) as u64;
+        }
+
+        let mut left = amount;
+        for order in &self.orders {
+            let order_total = order.amount + order.hidden;
+            let is_own = owner.is_some() && order.owner == owner;
+            if !(is_own || (order.all_or_none && order_total > left)) {
+                left -= order_total.min(left);
+            }
+        }
+
+        amount - left
+    }
+
+    // Shares `amount` out in time priority, passing over all-or-none orders
+    // too big for what is left of it
+    fn allocate_around_all_or_none(&self, mut amount: u64, allocations: &mut Vec<(usize, u64)>) {
+        for (index, order) in self.orders.iter().enumerate() {
+            if amount == 0 {
+                break;
+            }
+            if order.all_or_none && order.amount > amount {
+                continue;
+            }
+
+            let share = order.amount.min(amount);
+            allocations.push((index, share));
+            amount -= share;
+        }
+    }
+
+    // Shares `amount` out the way a sweep does: every order in full once it
+    // covers the level, in time priority around all-or-none orders too big
+    // for it, otherwise as `policy` says
+    fn share_out(&self, amount: u64, policy: &impl MatchingPolicy, allocations: &mut Vec<(usize, u64)>) {
+        if u128::from(amount) >= self.quantity {
+            allocations.extend(self.orders.iter().map(|order| order.amount).enumerate());
+        } else if self.all_or_none_count > 0 {
+            self.allocate_around_all_or_none(amount, allocations);
+        } else {
+            policy.allocate(self, amount, allocations);
+        }
+    }
+
+    // Sweeps a copy of the level the way `BookSide::take` would for a taker
+    // of `amount` from `owner`, returning how much trades here and how much
+    // of `amount` is left after, `None` once self-trade prevention ends the
+    // sweep. Only needed where one of `owner`'s orders rests and self-trade
+    // prevention does not simply cancel it.
+    fn sweep_around_own(
+        &self,
+        amount: u64,
+        owner: Option<OwnerId>,
+        matching: (&impl MatchingPolicy, SelfTradePrevention)
+    ) -> (u64, Option<u64>) {
+        let (policy, self_trade_prevention) = matching;
+        let mut level = self.clone();
+        let mut allocations = Vec::new();
+        let mut refills = Vec::new();
+        // Nothing looks orders up on the copy, so any seq will do
+        let mut next_seq = 0;
+        let mut left = amount;
+        let mut traded = 0;
+
+        while left > 0 {
+            allocations.clear();
+            level.share_out(left, policy, &mut allocations);
+            // Empty once only all-or-none orders too big to fill are left
+            if allocations.is_empty() {
+                break;
+            }
+
+            let mut used_up_count = 0;
+            for &(index, share) in &allocations {
+                let order = &mut level.orders[index];
+                let is_own = owner.is_some() && order.owner == owner;
+
+                let share = if is_own {
+                    if self_trade_prevention != SelfTradePrevention::DecrementBoth {
+                        return (traded, None);
+                    }
+                    order.amount.min(left)
+                } else {
+                    traded += share;
+                    share
+                };
+                order.amount -= share;
+                level.quantity -= u128::from(share);
+                left -= share;
+                used_up_count += (order.amount == 0) as usize;
+
+                if is_own {
+                    break;
+                }
+            }
+
+            level.take_used_up(used_up_count, |order| {
+                if order.hidden > 0 {
+                    refills.push(order);
+                }
+            });
+            for order in refills.drain(..) {
+                level.refill(order, &mut next_seq);
+            }
+        }
+
+        (traded, Some(left))
+    }
+
+    fn push_back(&mut self, order: Order) {
+        self.quantity += u128::from(order.amount);
+        self.hidden += u128::from(order.hidden);
+        self.pegged_count += order.peg.is_some() as usize;
+        self.all_or_none_count += order.all_or_none as usize;
+        self.orders.push_back(order);
+    }
+
+    fn pop_front(&mut self) -> Option<Order> {
+        let order = self.orders.pop_front()?;
+        self.quantity -= u128::from(order.amount);
+        self.hidden -= u128::from(order.hidden);
+        self.pegged_count -= order.peg.is_some() as usize;
+        self.all_or_none_count -= order.all_or_none as usize;
+
+        Some(order)
+    }
+
+    fn remove(&mut self, index: usize) -> Option<Order> {
+        let order = self.orders.remove(index)?;
+        self.quantity -= u128::from(order.amount);
+        self.hidden -= u128::from(order.hidden);
+        self.pegged_count -= order.peg.is_some() as usize;
+        self.all_or_none_count -= order.all_or_none as usize;
+
+        Some(order)
+    }
+
+    // Takes out the `used_up_count` orders with nothing left to show, handing
+    // each to `used_up`
+    fn take_used_up(&mut self, used_up_count: usize, mut used_up: impl FnMut(Order)) {
+        // Time priority uses up orders from the front, so this is usually all
+        let mut dropped_count = 0;
+        while dropped_count < used_up_count && self.orders.front().is_some_and(|order| order.amount == 0) {
+            used_up(self.pop_front().unwrap());
+            dropped_count += 1;
+        }
+
+        if dropped_count < used_up_count {
+            let mut rest = Vec::new();
+            self.orders.retain(|&order| {
+                if order.amount == 0 {
+                    rest.push(order);
+                }
+                order.amount > 0
+            });
+            for order in rest {
+                self.hidden -= u128::from(order.hidden);
+                self.pegged_count -= order.peg.is_some() as usize;
+                self.all_or_none_count -= order.all_or_none as usize;
+                used_up(order);
+            }
+        }
+    }
+
+    // Shows the next peak of a used up iceberg's reserve, joining the back of
+    // the level like a new order. Returns its new seq.
+    fn refill(&mut self, mut order: Order, next_seq: &mut u64) -> u64 {
+        let peak = order.peak.unwrap_or(order.hidden);
+        order.amount = peak.min(order.hidden);
+        order.hidden -= order.amount;
+        order.seq = *next_seq;
+        *next_seq += 1;
+        self.push_back(order);
+
+        order.seq
+    }
+
+    // Takes out the `used_up_count` orders with nothing left to show. Icebergs
+    // among them refill and rejoin at the back, the rest leave the book and
+    // their peg groups. Returns how many left.
+    fn drop_used_up(
+        &mut self,
+        used_up_count: usize,
+        next_seq: &mut u64,
+        order_id_to_location: &mut HashMap<OrderId, Location>,
+        peg_groups: &mut BTreeMap<Peg, PegGroup>
+    ) -> u64 {
+        let mut refills = Vec::new();
+        let mut left_count = 0;
+        self.take_used_up(used_up_count, |order| {
+            if order.hidden > 0 {
+                refills.push(order);
+            } else {
+                left_count += 1;
+                order_id_to_location.remove(&order.id);
+                leave_peg_group(peg_groups, &order);
+            }
+        });
+
+        for order in refills {
+            let seq = self.refill(order, next_seq);
+            order_id_to_location.get_mut(&order.id).unwrap().seq = seq;
+        }
+
+        left_count
+    }
+
+    fn find(&self, seq: u64) -> usize {
+        self.orders
+            .binary_search_by_key(&seq, |order| order.seq)
+            .expect("No order in level.")
+    }
+}
+
+/// One side of the book, bids or asks.
+#[derive(Debug, Clone)]
+struct BookSide {
+    side: Side,
+    // BTreeMap of a Level (IndexMap does not allow for the same size value!)
+    // Price -> Sorted Orders
+    price_to_level: BTreeMap<u64, Level>,
+    // Price -> number of orders, for finding the k-th order without walking levels
+    order_counts: CountedTree,
+    next_seq: u64,
+    // Scratch space for a policy's shares, kept to save allocating per take
+    allocations: Vec<(usize, u64)>,
+    // Trigger price -> stops on this side waiting for it, in arrival order
+    trigger_to_stops: BTreeMap<u64, Vec<(OrderId, Stop)>>,
+    // Orders sharing a peg sit at one price and move together
+    peg_groups: BTreeMap<Peg, PegGroup>,
+}
+
+#[derive(Debug, Clone)]
+struct PegGroup {
+    price: u64,
+    // In the order they queue at `price`, each taken out as its order leaves
+    // the book
+    ids: Vec<OrderId>,
+}
+
+// Takes `order` out of its peg group, if it has one, and the group out of
+// `peg_groups` once nothing is left in it. Orders mostly leave from the front
+// of their level, so the search is short.
+fn leave_peg_group(peg_groups: &mut BTreeMap<Peg, PegGroup>, order: &Order) {
+    let Some(Entry::Occupied(mut group)) = order.peg.map(|peg| peg_groups.entry(peg)) else {
+        return;
+    };
+
+    let ids = &mut group.get_mut().ids;
+    if let Some(index) = ids.iter().position(|&id| id == order.id) {
+        ids.remove(index);
+    }
+    if ids.is_empty() {
+        group.remove();
+    }
+}
+
+impl BookSide {
+    fn new(side: Side) -> Self {
+        Self {
+            side,
+            price_to_level: BTreeMap::new(),
+            order_counts: CountedTree::default(),
+            next_seq: 0,
+            allocations: Vec::new(),
+            trigger_to_stops: BTreeMap::new(),
+            peg_groups: BTreeMap::new(),
+        }
+    }
+
+    // Bids are best at the highest price, asks at the lowest
+    fn best_price(&self) -> Option<u64> {
+        match self.side {
+            Side::Buy => self.price_to_level.keys().next_back().copied(),
+            Side::Sell => self.price_to_level.keys().next().copied(),
+        }
+    }
+
+    // Best price among orders with a price of their own, what pegs follow
+    fn reference_price(&self) -> Option<u64> {
+        self.levels()
+            .find(|(_, level)| level.orders.len() > level.pegged_count)
+            .map(|(&price, _)| price)
+    }
+
+    // Levels from the best price on
+    fn levels(&self) -> impl Iterator<Item = (&u64, &Level)> {
+        self.levels_in(..)
+    }
+
+    // Levels priced within `prices`, from the best price on
+    fn levels_in(&self, prices: impl RangeBounds<u64>) -> impl Iterator<Item = (&u64, &Level)> {
+        let levels = self.price_to_level.range(prices);
+        let (forward, backward) = match self.side {
+            Side::Buy => (None, Some(levels.rev())),
+            Side::Sell => (Some(levels), None),
+        };
+
+        forward.into_iter().flatten().chain(backward.into_iter().flatten())
+    }
+
+    // Best price after `after`, or the best of all without it
+    fn next_price(&self, after: Option<u64>) -> Option<u64> {
+        let Some(after) = after else {
+            return self.best_price();
+        };
+
+        let next = match self.side {
+            Side::Buy => self.price_to_level.range(..after).next_back(),
+            Side::Sell => self.price_to_level.range((Bound::Excluded(after), Bound::Unbounded)).next(),
+        };
+        next.map(|(&price, _)| price)
+    }
+
+    // Whether an order resting here at `price` trades with an incoming order limited to `limit`
+    fn crosses(&self, price: u64, limit: u64) -> bool {
+        match self.side {
+            Side::Buy => price >= limit,
+            Side::Sell => price <= limit,
+        }
+    }
+
+    // Rests `amount` of `order` at the back of its level, showing at most
+    // its peak, returns the order's seq
+    fn push(&mut self, id: OrderId, order: &NewOrder, amount: u64) -> u64 {
+        let seq = self.next_seq;
+        self.next_seq += 1;
+
+        let level = self.price_to_level.entry(order.price).or_default();
+        let shown = order.peak.map_or(amount, |peak| peak.min(amount));
+
+        // Probably better to use something array based-ish
+        level.push_back(Order {
+            id,
+            amount: shown,
+            seq,
+            hidden: amount - shown,
+            peak: order.peak,
+            peg: order.peg,
+            owner: order.owner,
+            all_or_none: order.all_or_none,
+        });
+        self.order_counts.increment(order.price, 1);
+
+        seq
+    }
+
+    fn remove_stop(&mut self, trigger: u64, id: OrderId) -> Order {
+        let stops = self.trigger_to_stops.get_mut(&trigger).expect("No stops for trigger.");
+        let index = stops.binary_search_by_key(&id, |&(id, _)| id).expect("No stop for id.");
+        let (_, stop) = stops.remove(index);
+
+        if stops.is_empty() {
+            self.trigger_to_stops.remove(&trigger);
+        }
+
+        Order {
+            id,
+            amount: stop.amount,
+            seq: 0,
+            hidden: 0,
+            peak: None,
+            peg: None,
+            owner: stop.owner,
+            all_or_none: false,
+        }
+    }
+
+    fn remove(&mut self, price: u64, seq: u64) -> Order {
+        let level = self.price_to_level.get_mut(&price).expect("No level for price.");
+        let index_in_level = level.find(seq);
+        let order = level.remove(index_in_level).unwrap();
+        self.order_counts.decrement(price, 1);
+        leave_peg_group(&mut self.peg_groups, &order);
+
+        if level.orders.is_empty() {
+            self.price_to_level.remove(&price);
+        }
+
+        order
+    }
+
+    // Id of the order at `index`. Positions count from the best price on, so
+    // bids count down from the top.
+    fn id_at(&self, index: u64) -> Option<OrderId> {
+        let (price, index_in_level) = self.order_counts.select(index, self.side == Side::Buy)?;
+
+        Some(self.price_to_level[&price].orders[index_in_level as usize].id)
+    }
+
+    fn top(&self) -> Option<(u64, u128)> {
+        self.best_price().map(|price| (price, self.price_to_level[&price].quantity))
+    }
+
+    // How much of `amount` the levels up to `limit` could fill for a taker
+    // from `owner`. Cancelling the taker's own orders leaves the rest of their
+    // level to fill. Under any other self-trade prevention a level holding one
+    // is swept on a copy, since the sweep trades whatever it reaches first
+    // there and then ends, or goes on with less.
+    fn liquidity(
+        &self,
+        amount: u64,
+        limit: Option<u64>,
+        owner: Option<OwnerId>,
+        matching: (&impl MatchingPolicy, SelfTradePrevention)
+    ) -> u64 {
+        let (_, self_trade_prevention) = matching;
+        let mut liquidity = 0;
+        let mut left = amount;
+
+        for (&price, level) in self.levels() {
+            if left == 0 || limit.is_some_and(|limit| !self.crosses(price, limit)) {
+                break;
+            }
+
+            let holds_own = owner.is_some() && level.orders.iter().any(|order| order.owner == owner);
+            if !holds_own || self_trade_prevention == SelfTradePrevention::CancelResting {
+                let fillable = level.fillable(left, owner);
+                liquidity += fillable;
+                left -= fillable;
+                continue;
+            }
+
+            let (traded, rest) = level.sweep_around_own(left, owner, matching);
+            liquidity += traded;
+            match rest {
+                Some(rest) => left = rest,
+                None => break,
+            }
+        }
+
+        liquidity
+    }
+
+    // Walks the level totals the way `take` would, without consuming them
+    fn quote(&self, amount: u64) -> Quote {
+        let mut quote = Quote {
+            filled: 0,
+            cost: 0,
+            worst_price: None,
+            levels: 0,
+        };
+
+        for (&price, level) in self.levels() {
+            if quote.filled == amount {
+                break;
+            }
+
+            let level_amount = level.fillable(amount - quote.filled, None);
+            if level_amount == 0 {
+                continue;
+            }
+
+            quote.filled += level_amount;
+            quote.cost += notional(price, level_amount);
+            quote.worst_price = Some(price);
+            quote.levels += 1;
+        }
+
+        quote
+    }
+
+    // Walks the level totals buying whole units until the next one would go
+    // over `budget`. Prices only get worse further in, so once the budget
+    // runs out at one level nothing further in fits. A level left short by
+    // all-or-none orders too big for the budget is passed over instead, the
+    // way `quote` does.
+    fn quote_notional(&self, budget: u64) -> Quote {
+        let mut quote = Quote {
+            filled: 0,
+            cost: 0,
+            worst_price: None,
+            levels: 0,
+        };
+
+        for (&price, level) in self.levels() {
+            // The cost never goes over the budget, so neither does this
+            let left = u128::from(budget) - quote.cost;
+            let affordable = left.checked_div(u128::from(price)).map_or(u64::MAX, |affordable| affordable as u64);
+            if affordable == 0 {
+                break;
+            }
+
+            let level_amount = level.fillable(affordable, None);
+            if level_amount > 0 {
+                quote.filled += level_amount;
+                quote.cost += notional(price, level_amount);
+                quote.worst_price = Some(price);
+                quote.levels += 1;
+            }
+
+            if level_amount == affordable {
+                break;
+            }
+        }
+
+        quote
+    }
+
+    // Takes out the stops trades from `low` to `high` set off, buy stops from
+    // `high` down and sell stops from `low` up
+    fn triggered_stops(&mut self, (low, high): (u64, u64)) -> BTreeMap<u64, Vec<(OrderId, Stop)>> {
+        match self.side {
+            Side::Buy => up_to(&mut self.trigger_to_stops, high),
+            Side::Sell => self.trigger_to_stops.split_off(&low),
+        }
+    }
+
+    // Consumes up to `amount` from the best price on, stopping at `limit` if
+    // there is one, sharing each level out as `policy` says. A level holding
+    // all-or-none orders the amount cannot cover goes in time priority
+    // instead, passing over them. A taker with an owner never trades with
+    // orders of the same owner, `self_trade_prevention` says what happens
+    // instead.
+    fn take(
+        &mut self,
+        amount: u64,
+        limit: Option<u64>,
+        owner: Option<OwnerId>,
+        matching: (&impl MatchingPolicy, SelfTradePrevention),
+        order_id_to_location: &mut HashMap<OrderId, Location>,
+        listener: &mut impl BookListener
+    ) -> Swept {
+        let (policy, self_trade_prevention) = matching;
+        let side = self.side;
+        let mut remaining_amount = amount;
+        let mut cost = 0;
+        let mut first_price = None;
+        let mut last_price = None;
+        let mut dropped = 0;
+        // Last level left holding only all-or-none orders too big to fill
+        let mut passed_price = None;
+
+        while remaining_amount > 0 {
+            let Some(best_price) = self.next_price(passed_price) else {
+                break;
+            };
+            if limit.is_some_and(|limit| !self.crosses(best_price, limit)) {
+                break;
+            }
+
+            let Entry::Occupied(mut best_level_entry) = self.price_to_level.entry(best_price) else {
+                unreachable!("No level for price.");
+            };
+            let best_level = best_level_entry.get_mut();
+            let is_own = |order: &Order| owner.is_some() && order.owner == owner;
+
+            // The whole level goes in one step, its orders are only walked to
+            // report them and drop their ids. Icebergs refill and requeue as
+            // they go, so a level holding any reserve is never taken this way,
+            // and nor is one holding an order of the taker's own.
+            let covers_level = u128::from(remaining_amount) >= best_level.quantity;
+            if covers_level && best_level.hidden == 0 && !best_level.orders.iter().any(is_own) {
+                // No more than `remaining_amount`, so it fits
+                let quantity = best_level.quantity as u64;
+                first_price.get_or_insert(best_price);
+                last_price = Some(best_price);
+                remaining_amount -= quantity;
+                cost += notional(best_price, quantity);
+                self.order_counts.decrement(best_price, best_level.order_count() as u64);
+
+                for order in best_level_entry.remove().orders {
+                    order_id_to_location.remove(&order.id);
+                    leave_peg_group(&mut self.peg_groups, &order);
+                    let fill = Fill {
+                        maker_id: order.id,
+                        price: best_price,
+                        amount: order.amount,
+                        maker_remaining: 0,
+                    };
+                    listener.on_event(BookEvent::OrderFilled { side, fill });
+                }
+                listener.on_event(BookEvent::LevelRemoved { side, price: best_price });
+                continue;
+            }
+
+            // Otherwise the orders are filled one by one. An amount that covers
+            // every shown order fills them all under any policy, short of that
+            // the policy shares it out.
+            self.allocations.clear();
+            best_level.share_out(remaining_amount, policy, &mut self.allocations);
+
+            let mut used_up_count = 0;
+            for &(index, share) in &self.allocations {
+                let order = &mut best_level.orders[index];
+
+                // The shares after this one were worked out for an amount that
+                // may be about to change, so the level is shared out afresh
+                if is_own(order) {
+                    let (id, price) = (order.id, best_price);
+                    match self_trade_prevention {
+                        SelfTradePrevention::CancelResting | SelfTradePrevention::CancelBoth => {
+                            let amount = order.amount + order.hidden;
+                            best_level.quantity -= u128::from(order.amount);
+                            best_level.hidden -= u128::from(order.hidden);
+                            order.amount = 0;
+                            order.hidden = 0;
+                            listener.on_event(BookEvent::OrderCancelled { id, side, price, amount });
+                        },
+                        SelfTradePrevention::DecrementBoth => {
+                            let cut = order.amount.min(remaining_amount);
+                            order.amount -= cut;
+                            best_level.quantity -= u128::from(cut);
+                            remaining_amount -= cut;
+                            dropped += cut;
+
+                            let amount = order.amount + order.hidden;
+                            listener.on_event(if amount == 0 {
+                                BookEvent::OrderCancelled { id, side, price, amount: cut }
+                            } else {
+                                BookEvent::OrderReduced { id, side, price, amount }
+                            });
+                        },
+                        SelfTradePrevention::CancelIncoming => (),
+                    }
+
+                    if matches!(
+                        self_trade_prevention,
+                        SelfTradePrevention::CancelIncoming | SelfTradePrevention::CancelBoth
+                    ) {
+                        dropped += remaining_amount;
+                        remaining_amount = 0;
+                    }
+                    if order.amount == 0 {
+                        used_up_count += 1;
+                    }
+                    break;
+                }
+
+                first_price.get_or_insert(best_price);
+                last_price = Some(best_price);
+                order.amount -= share;
+                best_level.quantity -= u128::from(share);
+                remaining_amount -= share;
+                cost += notional(best_price, share);
+                let fill = Fill {
+                    maker_id: order.id,
+                    price: best_price,
+                    amount: share,
+                    maker_remaining: order.amount + order.hidden,
+                };
+                listener.on_event(BookEvent::OrderFilled { side, fill });
+
+                if order.amount == 0 {
+                    used_up_count += 1;
+                }
+            }
+
+            if used_up_count > 0 {
+                let left_count = best_level.drop_used_up(
+                    used_up_count,
+                    &mut self.next_seq,
+                    order_id_to_location,
+                    &mut self.peg_groups
+                );
+                if left_count > 0 {
+                    self.order_counts.decrement(best_price, left_count);
+                }
+
+                if best_level.orders.is_empty() {
+                    best_level_entry.remove();
+                    listener.on_event(BookEvent::LevelRemoved { side, price: best_price });
+                }
+            }
+
+            let passed_over = |level: &Level| {
+                level.orders.iter().all(|order| order.all_or_none && order.amount > remaining_amount)
+            };
+            if remaining_amount > 0 && self.price_to_level.get(&best_price).is_some_and(passed_over) {
+                passed_price = Some(best_price);
+            }
+        }
+
+        Swept {
+            remaining: remaining_amount,
+            cost,
+            first_price,
+            last_price,
+            dropped,
+        }
+    }
+}
+
+// What one sweep through a side did
+struct Swept {
+    remaining: u64,
+    cost: u128,
+    // Prices of the first and last trades, if there were any. Prices only
+    // get worse through a sweep, so every trade lies between the two.
+    first_price: Option<u64>,
+    last_price: Option<u64>,
+    // Taken off the incoming order by self-trade prevention without trading,
+    // on top of what is `remaining`
+    dropped: u64,
+}
+
+// Where a resting order sits
+#[derive(Debug, Clone, Copy)]
+struct Location {
+    side: Side,
+    price: u64,
+    seq: u64,
+}
+
+/// Two-sided price-priority matching book.
+///
+/// Orders are kept per price level in arrival order. An incoming order trades
+/// with the best opposite prices first, bids from the highest price down and
+/// asks from the lowest price up. Within a level `P` decides who trades, strict
+/// time priority unless the book is made `with_policy`.
+///
+/// All-or-none orders only ever trade in full. One that cannot on arrival
+/// rests whole, even where it crosses, and sweeps too small for it pass it
+/// over without taking the places of the orders around it.
+///
+/// Orders whose time in force runs out drop off at the start of the next
+/// change to the book, before anything can trade with them. `C` tells the
+/// time.
+///
+/// Pegged orders are repriced once every change has played out. A repriced
+/// order joins the back of its new level like a new order, and orders sharing
+/// a peg move together in the order they queued. Bid pegs move before ask
+/// pegs, each side in `Peg` order.
+#[derive(Debug, Clone)]
+pub struct OrderBook<P = Fifo, C = SystemClock> {
+    bids: BookSide,
+    asks: BookSide,
+    // Every order joins the back of its level with a growing seq, so a binary
+    // search on the seq finds its slot
+    order_id_to_location: HashMap<OrderId, Location>,
+    // Stops waiting off the book -> where to find them in `trigger_to_stops`
+    stop_id_to_trigger: HashMap<OrderId, (Side, u64)>,
+    // Expiry -> ids of the orders that rested with it, in arrival order. Ids
+    // of orders that have since left the book are dropped when they come due,
+    // or sooner once they could outnumber the orders on the book.
+    time_to_expiring: BTreeMap<u64, Vec<OrderId>>,
+    sequence_to_expiring: BTreeMap<u64, Vec<OrderId>>,
+    // Ids across both expiry indexes, whether their orders are there or not
+    expiring_len: usize,
+    next_order_id: OrderId,
+    last_price: Option<u64>,
+    // Lowest and highest prices traded since stops were last checked
+    traded_range: Option<(u64, u64)>,
+    policy: P,
+    self_trade_prevention: SelfTradePrevention,
+    clock: C,
+    // Stands in for the clock while `apply_at` runs a command
+    pinned_now: Option<u64>,
+}
+
+impl<P: MatchingPolicy + Default, C: Clock + Default> Default for OrderBook<P, C> {
+    fn default() -> Self {
+        Self::with_clock(P::default(), C::default())
+    }
+}
+
+impl OrderBook {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl<P: MatchingPolicy> OrderBook<P> {
+    /// An empty book sharing partly taken levels out as `policy` says.
+    pub fn with_policy(policy: P) -> Self {
+        Self::with_clock(policy, SystemClock)
+    }
+}
+
+impl<P: MatchingPolicy, C: Clock> OrderBook<P, C> {
+    /// An empty book sharing partly taken levels out as `policy` says and
+    /// expiring good-till-time orders by `clock`.
+    pub fn with_clock(policy: P, clock: C) -> Self {
+        Self {
+            bids: BookSide::new(Side::Buy),
+            asks: BookSide::new(Side::Sell),
+            order_id_to_location: HashMap::new(),
+            stop_id_to_trigger: HashMap::new(),
+            time_to_expiring: BTreeMap::new(),
+            sequence_to_expiring: BTreeMap::new(),
+            expiring_len: 0,
+            next_order_id: 0,
+            last_price: None,
+            traded_range: None,
+            policy,
+            self_trade_prevention: SelfTradePrevention::default(),
+            clock,
+            pinned_now: None,
+        }
+    }
+
+    /// Decides what happens when an order would trade with a resting order of
+    /// the same owner from now on.
+    pub fn set_self_trade_prevention(&mut self, self_trade_prevention: SelfTradePrevention) {
+        self.self_trade_prevention = self_trade_prevention;
+    }
+
+    fn book_side(&self, side: Side) -> &BookSide {
+        match side {
+            Side::Buy => &self.bids,
+            Side::Sell => &self.asks,
+        }
+    }
+
+    fn book_side_mut(&mut self, side: Side) -> &mut BookSide {
+        match side {
+            Side::Buy => &mut self.bids,
+            Side::Sell => &mut self.asks,
+        }
+    }
+
+    /// Submits a good-till-cancel limit order for `amount` at `price`.
+    ///
+    /// Whatever crosses the opposite side trades first, the rest joins the
+    /// back of its own level.
+    pub fn add(&mut self, side: Side, price: u64, amount: u64) -> Result<Added> {
+        self.submit(NewOrder::limit(side, price, amount))
+    }
+
+    /// Same as `add`, reporting every change to the book to `listener`.
+    pub fn add_into(&mut self, side: Side, price: u64, amount: u64, listener: &mut impl BookListener) -> Result<Added> {
+        self.submit_into(NewOrder::limit(side, price, amount), listener)
+    }
+
+    /// Submits a limit order, trading and resting as its `time_in_force` says.
+    ///
+    /// A fill-or-kill order the book cannot fill in full, all-or-none
+    /// immediate-or-cancel orders included, an order that cannot trade its
+    /// minimum quantity, a post-only order that would cross,
+    /// a pegged order with nothing to follow, an all-or-none iceberg and an
+    /// order that has already expired fail with `Error::Rejected`, leaving
+    /// the book as it was and using up no id. What the book can fill leaves
+    /// out the order's own resting orders, and unless self-trade prevention
+    /// cancels those, everything the sweep would reach after the first one.
+    pub fn submit(&mut self, order: NewOrder) -> Result<Added> {
+        self.submit_into(order, &mut ())
+    }
+
+    /// Same as `submit`, reporting every change to the book to `listener`.
+    pub fn submit_into(&mut self, mut order: NewOrder, listener: &mut impl BookListener) -> Result<Added> {
+        self.reported(listener, |book, listener| {
+            book.expire_due(listener);
+
+            if order.amount == 0 || order.peak == Some(0) {
+                return Err(Error::ZeroAmount);
+            }
+            if order.all_or_none && order.peak.is_some() {
+                return Err(Error::Rejected { reason: Reject::AllOrNoneIceberg });
+            }
+
+            if let Some(peg) = order.peg {
+                order.price = book.peg_price(order.side, peg).ok_or(Error::Rejected { reason: Reject::NoReference })?;
+            }
+
+            let opposite_book_side = book.book_side(order.side.opposite());
+            let fill_or_kill = order.time_in_force == TimeInForce::FillOrKill
+                || (order.time_in_force == TimeInForce::ImmediateOrCancel && order.all_or_none);
+            let reject = match order.time_in_force {
+                TimeInForce::FillOrKill | TimeInForce::ImmediateOrCancel if fill_or_kill => {
+                    let liquidity = book.liquidity(order.side, order.amount, Some(order.price), order.owner);
+                    (liquidity < order.amount).then_some(Reject::NotEnoughLiquidity)
+                },
+                TimeInForce::PostOnly => {
+                    let crosses = opposite_book_side.best_price()
+                        .is_some_and(|best_price| opposite_book_side.crosses(best_price, order.price));
+                    crosses.then_some(Reject::WouldCross)
+                },
+                TimeInForce::GoodTillTime(time) => (time <= book.now()).then_some(Reject::Expired),
+                TimeInForce::GoodTillSequence(sequence) => (sequence <= book.next_order_id).then_some(Reject::Expired),
+                _ => None,
+            };
+            let reject = reject.or_else(|| {
+                let min_quantity = order.min_quantity?;
+                let liquidity = book.liquidity(order.side, min_quantity, Some(order.price), order.owner);
+                (liquidity < min_quantity).then_some(Reject::NotEnoughLiquidity)
+            });
+            if let Some(reason) = reject {
+                return Err(Error::Rejected { reason });
+            }
+
+            let id = book.next_order_id;
+            book.next_order_id += 1;
+
+            // Neither rests, so both go through the same sweep as a take
+            if matches!(order.time_in_force, TimeInForce::ImmediateOrCancel | TimeInForce::FillOrKill) {
+                let mode = if fill_or_kill { TakeMode::FillOrKill } else { TakeMode::Partial };
+                let taken = book.sweep(order.side, order.amount, Some(order.price), order.owner, mode, listener)?;
+
+                return Ok(Added {
+                    id,
+                    filled: taken.filled,
+                    cost: taken.cost,
+                    resting: 0,
+                    cancelled: taken.unfilled,
+                });
+            }
+
+            let added = book.place(id, &order, listener);
+            if added.resting > 0 {
+                book.index_expiry(id, order.time_in_force);
+            }
+            book.settle(listener);
+
+            Ok(added)
+        })
+    }
+
+    // Files the resting order with `id` under the expiry its time in force
+    // gives it, if any. Ids left behind by orders that filled or were
+    // cancelled are cleared out once there are twice as many ids as orders on
+    // the book, so the indexes never grow much past the book.
+    fn index_expiry(&mut self, id: OrderId, time_in_force: TimeInForce) {
+        let (expiring, expiry) = match time_in_force {
+            TimeInForce::GoodTillTime(time) => (&mut self.time_to_expiring, time),
+            TimeInForce::GoodTillSequence(sequence) => (&mut self.sequence_to_expiring, sequence),
+            _ => return,
+        };
+        expiring.entry(expiry).or_default().push(id);
+        self.expiring_len += 1;
+
+        if self.expiring_len > 2 * self.order_id_to_location.len() {
+            let order_id_to_location = &self.order_id_to_location;
+            self.expiring_len = 0;
+
+            for expiring in [&mut self.time_to_expiring, &mut self.sequence_to_expiring] {
+                expiring.retain(|_, ids| {
+                    ids.retain(|id| order_id_to_location.contains_key(id));
+                    !ids.is_empty()
+                });
+                self.expiring_len += expiring.values().map(Vec::len).sum::<usize>();
+            }
+        }
+    }
+
+    /// Holds `stop` off the book until a trade reaches its trigger, then sends
+    /// it in under the returned id.
+    ///
+    /// A stop whose trigger the last trade has already reached goes in at once.
+    pub fn submit_stop(&mut self, stop: Stop) -> Result<OrderId> {
+        self.submit_stop_into(stop, &mut ())
+    }
+
+    /// Same as `submit_stop`, reporting every change to the book to `listener`.
+    pub fn submit_stop_into(&mut self, stop: Stop, listener: &mut impl BookListener) -> Result<OrderId> {
+        self.reported(listener, |book, listener| {
+            book.expire_due(listener);
+
+            if stop.amount == 0 {
+                return Err(Error::ZeroAmount);
+            }
+
+            let id = book.next_order_id;
+            book.next_order_id += 1;
+
+            book.book_side_mut(stop.side).trigger_to_stops.entry(stop.trigger).or_default().push((id, stop));
+            book.stop_id_to_trigger.insert(id, (stop.side, stop.trigger));
+            book.settle(listener);
+
+            Ok(id)
+        })
+    }
+
+    // Plays out whatever a change to the book sets off: stops the last trade
+    // reached, then pegged orders following the new top of book, until
+    // neither has anything left to do. Moving pegs leaves the references
+    // alone unless they trade, so this ends once trading does.
+    fn settle(&mut self, listener: &mut impl BookListener) {
+        loop {
+            self.fire_stops(listener);
+            if !self.reprice_pegs(listener) {
+                break;
+            }
+        }
+    }
+
+    // Runs one call's `change` to the book, then tells `listener` of each side
+    // whose best price or quantity there it moved. Every public change goes
+    // through here once, whatever it calls on the way, so each move is
+    // reported once and against where the top stood when the call began.
+    fn reported<L: BookListener, T>(&mut self, listener: &mut L, change: impl FnOnce(&mut Self, &mut L) -> T) -> T {
+        let tops = [self.bids.top(), self.asks.top()];
+        let changed = change(self, listener);
+
+        for (book_side, top_before) in [&self.bids, &self.asks].into_iter().zip(tops) {
+            let top = book_side.top();
+            if top != top_before {
+                listener.on_event(BookEvent::TopOfBookChanged {
+                    side: book_side.side,
+                    price: top.map(|(price, _)| price),
+                    quantity: top.map_or(0, |(_, quantity)| quantity),
+                });
+            }
+        }
+
+        changed
+    }
+
+    // Takes the order with `id` off the book without trading it, the way a
+    // cancel does
+    fn remove_resting(&mut self, id: OrderId, listener: &mut impl BookListener) -> Option<Order> {
+        let Location { side, price, seq } = self.order_id_to_location.remove(&id)?;
+        let book_side = self.book_side_mut(side);
+        let order = book_side.remove(price, seq);
+        let level_removed = !book_side.price_to_level.contains_key(&price);
+
+        listener.on_event(BookEvent::OrderCancelled { id, side, price, amount: order.amount + order.hidden });
+        if level_removed {
+            listener.on_event(BookEvent::LevelRemoved { side, price });
+        }
+
+        Some(order)
+    }
+
+    // What an order pegged with `peg` on `side` would be priced at now
+    fn peg_price(&self, side: Side, peg: Peg) -> Option<u64> {
+        let reference = match peg.reference {
+            PegReference::BestBid => self.bids.reference_price()?,
+            PegReference::BestAsk => self.asks.reference_price()?,
+            PegReference::Midpoint => {
+                // Halfway between two `u64` prices is one too, their sum may not be
+                let sum = u128::from(self.bids.reference_price()?) + u128::from(self.asks.reference_price()?);
+                let midpoint = match side {
+                    Side::Buy => sum / 2,
+                    Side::Sell => sum.div_ceil(2),
+                };
+                midpoint as u64
+            },
+        };
+
+        reference.checked_add_signed(peg.offset)
+    }
+
+    // Moves every peg group whose price is out of date to the back of its new
+    // level, trading whatever the new price crosses. A group with nothing to
+    // follow stays where it is. Returns whether anything moved.
+    fn reprice_pegs(&mut self, listener: &mut impl BookListener) -> bool {
+        let mut moved = false;
+
+        for side in [Side::Buy, Side::Sell] {
+            let pegs = self.book_side(side).peg_groups.keys().copied().collect::<Vec<_>>();
+
+            for peg in pegs {
+                let Some(price) = self.peg_price(side, peg) else {
+                    continue;
+                };
+                let peg_groups = &mut self.book_side_mut(side).peg_groups;
+                if peg_groups.get(&peg).is_none_or(|group| group.price == price) {
+                    continue;
+                }
+
+                // The group forms again at `price` as its orders rest there
+                let group = peg_groups.remove(&peg).unwrap();
+                moved = true;
+
+                for id in group.ids {
+                    let order = self.remove_resting(id, listener).unwrap();
+
+                    let amount = order.amount + order.hidden;
+                    let moved = NewOrder {
+                        price,
+                        peak: order.peak,
+                        owner: order.owner,
+                        all_or_none: order.all_or_none,
+                        ..NewOrder::pegged(side, peg, amount)
+                    };
+                    self.place(id, &moved, listener);
+                }
+            }
+        }
+
+        moved
+    }
+
+    // Sends in every stop a trade since the last check set off, or the last
+    // trade for a stop that has just come in. Stops set off together go in by
+    // id, so in the order they came, and stops they set off in turn queue up
+    // behind them.
+    fn fire_stops(&mut self, listener: &mut impl BookListener) {
+        let mut triggered = VecDeque::new();
+
+        while let Some(traded_range) = self.traded_range.take().or(self.last_price.map(|price| (price, price))) {
+            let start = triggered.len();
+            triggered.extend(self.bids.triggered_stops(traded_range).into_values().flatten());
+            triggered.extend(self.asks.triggered_stops(traded_range).into_values().flatten());
+            triggered.make_contiguous()[start..].sort_unstable_by_key(|&(id, _)| id);
+
+            let Some((id, stop)) = triggered.pop_front() else {
+                break;
+            };
+            self.stop_id_to_trigger.remove(&id);
+
+            match stop.limit {
+                Some(limit) => {
+                    let order = NewOrder { owner: stop.owner, ..NewOrder::limit(stop.side, limit, stop.amount) };
+                    self.place(id, &order, listener);
+                },
+                None => {
+                    self.trade(stop.side, stop.amount, None, stop.owner, listener);
+                }
+            }
+        }
+    }
+
+    // How much of `amount` a `side` taker from `owner` could fill up to
+    // `limit`, with self-trade prevention as the book has it
+    fn liquidity(&self, side: Side, amount: u64, limit: Option<u64>, owner: Option<OwnerId>) -> u64 {
+        self.book_side(side.opposite()).liquidity(amount, limit, owner, (&self.policy, self.self_trade_prevention))
+    }
+
+    // Sweeps the side opposite a `side` taker, keeping track of the prices
+    // traded
+    fn trade(
+        &mut self,
+        side: Side,
+        amount: u64,
+        limit: Option<u64>,
+        owner: Option<OwnerId>,
+        listener: &mut impl BookListener
+    ) -> Swept {
+        let opposite_book_side = match side {
+            Side::Buy => &mut self.asks,
+            Side::Sell => &mut self.bids,
+        };
+        let matching = (&self.policy, self.self_trade_prevention);
+        let swept = opposite_book_side.take(amount, limit, owner, matching, &mut self.order_id_to_location, listener);
+
+        if let (Some(first_price), Some(last_price)) = (swept.first_price, swept.last_price) {
+            let (low, high) = (first_price.min(last_price), first_price.max(last_price));
+            self.traded_range = Some(self.traded_range.map_or((low, high), |(traded_low, traded_high)| {
+                (traded_low.min(low), traded_high.max(high))
+            }));
+            self.last_price = Some(last_price);
+        }
+
+        swept
+    }
+
+    // Trades whatever crosses, then rests the rest at the back of its level.
+    // An all-or-none order trades only if it fills in full. Leaves the time in
+    // force of `order` to the caller.
+    fn place(&mut self, id: OrderId, order: &NewOrder, listener: &mut impl BookListener) -> Added {
+        let NewOrder { side, price, amount, peg, owner, all_or_none, .. } = *order;
+
+        let swept = if all_or_none && self.liquidity(side, amount, Some(price), owner) < amount {
+            Swept { remaining: amount, cost: 0, first_price: None, last_price: None, dropped: 0 }
+        } else {
+            self.trade(side, amount, Some(price), owner, listener)
+        };
+
+        if swept.remaining > 0 {
+            let book_side = self.book_side_mut(side);
+            let level_created = !book_side.price_to_level.contains_key(&price);
+            let seq = book_side.push(id, order, swept.remaining);
+            if let Some(peg) = peg {
+                book_side.peg_groups.entry(peg).or_insert(PegGroup { price, ids: Vec::new() }).ids.push(id);
+            }
+            self.order_id_to_location.insert(id, Location { side, price, seq });
+
+            if level_created {
+                listener.on_event(BookEvent::LevelCreated { side, price });
+            }
+            listener.on_event(BookEvent::OrderAdded { id, side, price, amount: swept.remaining });
+        }
+
+        Added {
+            id,
+            filled: amount - swept.remaining - swept.dropped,
+            cost: swept.cost,
+            resting: swept.remaining,
+            cancelled: swept.dropped,
+        }
+    }
+
+    /// Changes the price and amount of the resting order with `id`.
+    ///
+    /// `amount` counts any iceberg reserve, which the order keeps. Moving a
+    /// pegged order pins it at `price`, its peg is dropped. Cutting the
+    /// amount at the same price keeps the order's place in its level, taking
+    /// from the reserve first. Raising the amount or moving the price sends it
+    /// to the back of the target level, after trading whatever the new price
+    /// crosses.
+    pub fn amend(&mut self, id: OrderId, price: u64, amount: u64) -> Result<Added> {
+        self.amend_into(id, price, amount, &mut ())
+    }
+
+    /// Same as `amend`, reporting every change to the book to `listener`.
+    pub fn amend_into(
+        &mut self,
+        id: OrderId,
+        price: u64,
+        amount: u64,
+        listener: &mut impl BookListener
+    ) -> Result<Added> {
+        self.reported(listener, |book, listener| {
+            book.expire_due(listener);
+
+            if amount == 0 {
+                return Err(Error::ZeroAmount);
+            }
+
+            let location = *book.order_id_to_location.get(&id).ok_or(Error::UnknownOrder { id })?;
+            let book_side = book.book_side_mut(location.side);
+
+            if price == location.price {
+                let level = book_side.price_to_level.get_mut(&price).unwrap();
+                let index_in_level = level.find(location.seq);
+                let order = &mut level.orders[index_in_level];
+
+                if amount <= order.amount + order.hidden {
+                    let shown = order.amount.min(amount);
+                    level.quantity -= u128::from(order.amount - shown);
+                    level.hidden -= u128::from(order.hidden - (amount - shown));
+                    order.amount = shown;
+                    order.hidden = amount - shown;
+
+                    listener.on_event(BookEvent::OrderReduced { id, side: location.side, price, amount });
+
+                    return Ok(Added {
+                        id,
+                        filled: 0,
+                        cost: 0,
+                        resting: amount,
+                        cancelled: 0,
+                    });
+                }
+            }
+
+            let order = book.remove_resting(id, listener).unwrap();
+            let moved = NewOrder {
+                peak: order.peak,
+                owner: order.owner,
+                all_or_none: order.all_or_none,
+                ..NewOrder::limit(location.side, price, amount)
+            };
+            let added = book.place(id, &moved, listener);
+            book.settle(listener);
+
+            Ok(added)
+        })
+    }
+
+    /// Removes the order with `id`, wherever it sits in its level, or the stop
+    /// with `id` that has not gone in yet.
+    ///
+    /// Fails with `Error::UnknownOrder` if the order never rested or has
+    /// already been filled or removed.
+    pub fn cancel(&mut self, id: OrderId) -> Result<Order> {
+        self.cancel_into(id, &mut ())
+    }
+
+    /// Same as `cancel`, reporting every change to the book to `listener`.
+    pub fn cancel_into(&mut self, id: OrderId, listener: &mut impl BookListener) -> Result<Order> {
+        self.reported(listener, |book, listener| {
+            book.expire_due(listener);
+
+            if let Some((side, trigger)) = book.stop_id_to_trigger.remove(&id) {
+                return Ok(book.book_side_mut(side).remove_stop(trigger, id));
+            }
+
+            let order = book.remove_resting(id, listener).ok_or(Error::UnknownOrder { id })?;
+            book.settle(listener);
+
+            Ok(order)
+        })
+    }
+
+    /// Removes the order at `index` on `side`, counted across its levels from
+    /// the best price on.
+    pub fn remove_at(&mut self, side: Side, index: u64) -> Result<Order> {
+        self.remove_at_into(side, index, &mut ())
+    }
+
+    /// Same as `remove_at`, reporting every change to the book to `listener`.
+    pub fn remove_at_into(&mut self, side: Side, index: u64, listener: &mut impl BookListener) -> Result<Order> {
+        self.reported(listener, |book, listener| {
+            book.expire_due(listener);
+
+            let id = book.book_side(side).id_at(index).ok_or(Error::IndexOutOfRange { index })?;
+            let order = book.remove_resting(id, listener).unwrap();
+            book.settle(listener);
+
+            Ok(order)
+        })
+    }
+
+    /// Takes `amount` for a `side` taker, sweeping the opposite side from its
+    /// best price on.
+    ///
+    /// `mode` decides what happens when the opposite side holds less than
+    /// `amount`: fill what is there, or fail with `Error::Rejected` and leave
+    /// the book as it was, the same as a fill-or-kill `submit`.
+    pub fn take(&mut self, side: Side, amount: u64, mode: TakeMode) -> Result<Taken> {
+        self.take_into(side, amount, mode, &mut ())
+    }
+
+    /// Same as `take`, reporting every change to the book to `listener`.
+    pub fn take_into(
+        &mut self,
+        side: Side,
+        amount: u64,
+        mode: TakeMode,
+        listener: &mut impl BookListener
+    ) -> Result<Taken> {
+        self.reported(listener, |book, listener| book.sweep(side, amount, None, None, mode, listener))
+    }
+
+    /// Same as `take`, but never trades at a price worse than `limit`: above
+    /// it for a buy, below it for a sell.
+    ///
+    /// Whatever is left once the sweep reaches `limit` comes back as
+    /// `unfilled` and does not rest.
+    pub fn take_up_to_price(&mut self, side: Side, amount: u64, limit: u64, mode: TakeMode) -> Result<Taken> {
+        self.take_up_to_price_into(side, amount, limit, mode, &mut ())
+    }
+
+    /// Same as `take_up_to_price`, reporting every change to the book to `listener`.
+    pub fn take_up_to_price_into(
+        &mut self,
+        side: Side,
+        amount: u64,
+        limit: u64,
+        mode: TakeMode,
+        listener: &mut impl BookListener
+    ) -> Result<Taken> {
+        self.reported(listener, |book, listener| book.sweep(side, amount, Some(limit), None, mode, listener))
+    }
+
+    fn sweep(
+        &mut self,
+        side: Side,
+        amount: u64,
+        limit: Option<u64>,
+        owner: Option<OwnerId>,
+        mode: TakeMode,
+        listener: &mut impl BookListener
+    ) -> Result<Taken> {
+        self.expire_due(listener);
+
+        if amount == 0 {
+            return Err(Error::ZeroAmount);
+        }
+
+        if mode == TakeMode::FillOrKill && self.liquidity(side, amount, limit, owner) < amount {
+            return Err(Error::Rejected { reason: Reject::NotEnoughLiquidity });
+        }
+
+        let swept = self.trade(side, amount, limit, owner, listener);
+        self.settle(listener);
+
+        Ok(Taken {
+            filled: amount - swept.remaining - swept.dropped,
+            cost: swept.cost,
+            unfilled: swept.remaining + swept.dropped,
+        })
+    }
+
+    /// What taking `amount` for a `side` taker would cost, leaving the book as
+    /// it is.
+    pub fn quote(&self, side: Side, amount: u64) -> Quote {
+        self.book_side(side.opposite()).quote(amount)
+    }
+
+    /// Takes as much as `budget` pays for, sweeping the opposite side from its
+    /// best price on and stopping before the unit that would go over budget.
+    pub fn take_notional(&mut self, side: Side, budget: u64) -> Result<Spent> {
+        self.take_notional_into(side, budget, &mut ())
+    }
+
+    /// Same as `take_notional`, reporting every change to the book to `listener`.
+    pub fn take_notional_into(&mut self, side: Side, budget: u64, listener: &mut impl BookListener) -> Result<Spent> {
+        self.reported(listener, |book, listener| {
+            book.expire_due(listener);
+
+            if budget == 0 {
+                return Err(Error::ZeroAmount);
+            }
+
+            // The sweep walks the same levels in the same order as the quote, so
+            // taking the quoted amount costs exactly the quoted cost
+            let quote = book.quote_notional(side, budget);
+            if quote.filled > 0 {
+                book.sweep(side, quote.filled, None, None, TakeMode::Partial, listener)?;
+            }
+
+            Ok(Spent {
+                filled: quote.filled,
+                cost: quote.cost,
+            })
+        })
+    }
+
+    /// What `take_notional` would buy for `budget`, leaving the book as it is.
+    pub fn quote_notional(&self, side: Side, budget: u64) -> Quote {
+        self.book_side(side.opposite()).quote_notional(budget)
+    }
+
+    /// Takes every order whose time in force has run out off the book.
+    ///
+    /// Every change to the book starts with this, call it to have orders drop
+    /// off while the book is otherwise quiet. Each expired order is reported
+    /// to the listener of the `_into` version as `OrderCancelled`, the same as
+    /// a cancel.
+    pub fn expire(&mut self) {
+        self.expire_into(&mut ());
+    }
+
+    /// Same as `expire`, reporting every change to the book to `listener`.
+    pub fn expire_into(&mut self, listener: &mut impl BookListener) {
+        self.reported(listener, |book, listener| book.expire_due(listener));
+    }
+
+    // Takes every order whose time in force has run out off the book, the
+    // start of every change to it
+    fn expire_due(&mut self, listener: &mut impl BookListener) {
+        let mut due_ids = Vec::new();
+
+        // The clock may cost a system call, so it is only read with some
+        // good-till-time order waiting
+        if let Some(&first_time) = self.time_to_expiring.keys().next() {
+            let now = self.now();
+            if first_time <= now {
+                due_ids.extend(up_to(&mut self.time_to_expiring, now).into_values().flatten());
+            }
+        }
+        if self.sequence_to_expiring.keys().next().is_some_and(|&first_sequence| first_sequence <= self.next_order_id) {
+            due_ids.extend(up_to(&mut self.sequence_to_expiring, self.next_order_id).into_values().flatten());
+        }
+        self.expiring_len -= due_ids.len();
+
+        let mut expired_any = false;
+        for id in due_ids {
+            // Gone already if it filled or was cancelled
+            expired_any |= self.remove_resting(id, listener).is_some();
+        }
+
+        if expired_any {
+            self.settle(listener);
+        }
+    }
+
+    /// Number of order ids handed out so far, what good-till-sequence orders
+    /// expire by.
+    pub fn sequence(&self) -> u64 {
+        self.next_order_id
+    }
+
+    /// Time by the book's clock, or the time `apply_at` is running a command
+    /// at.
+    pub fn now(&self) -> u64 {
+        self.pinned_now.unwrap_or_else(|| self.clock.now())
+    }
+
+    /// Same as `apply`, but with the clock reading `now` throughout, so a
+    /// replay expires good-till-time orders just where the first run did.
+    pub fn apply_at(&mut self, command: Command, now: u64) -> Result<()> {
+        self.apply_at_into(command, now, &mut ())
+    }
+
+    /// Same as `apply_at`, reporting every change to the book to `listener`.
+    pub fn apply_at_into(&mut self, command: Command, now: u64, listener: &mut impl BookListener) -> Result<()> {
+        let pinned_now = self.pinned_now.replace(now);
+        let applied = self.apply_into(command, listener);
+        self.pinned_now = pinned_now;
+
+        applied
+    }
+
+    /// Runs one `Command` against the book.
+    pub fn apply(&mut self, command: Command) -> Result<()> {
+        self.apply_into(command, &mut ())
+    }
+
+    /// Same as `apply`, reporting every change to the book to `listener`.
+    pub fn apply_into(&mut self, command: Command, listener: &mut impl BookListener) -> Result<()> {
+        match command {
+            Command::Add { side, price, amount, time_in_force } => {
+                self.submit_into(NewOrder { time_in_force, ..NewOrder::limit(side, price, amount) }, listener)?;
+            },
+            Command::RemoveAt { side, index } => {
+                self.remove_at_into(side, index, listener)?;
+            },
+            Command::Take { side, amount, limit: None, mode } => {
+                self.take_into(side, amount, mode, listener)?;
+            },
+            Command::Take { side, amount, limit: Some(limit), mode } => {
+                self.take_up_to_price_into(side, amount, limit, mode, listener)?;
+            },
+            Command::TakeNotional { side, budget } => {
+                self.take_notional_into(side, budget, listener)?;
+            },
+            Command::Amend { id, price, amount } => {
+                self.amend_into(id, price, amount, listener)?;
+            },
+            Command::Cancel { id } => {
+                self.cancel_into(id, listener)?;
+            },
+            Command::Submit(order) => {
+                self.submit_into(order, listener)?;
+            },
+            Command::SubmitStop(stop) => {
+                self.submit_stop_into(stop, listener)?;
+            },
+            Command::SetSelfTradePrevention(self_trade_prevention) => {
+                self.set_self_trade_prevention(self_trade_prevention);
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.order_id_to_location.is_empty()
+    }
+
+    /// Number of distinct price levels on `side`.
+    pub fn level_count(&self, side: Side) -> usize {
+        self.book_side(side).price_to_level.len()
+    }
+
+    /// The level at `price` on `side`, whose totals are kept up to date as
+    /// orders come and go.
+    pub fn level(&self, side: Side, price: u64) -> Option<&Level> {
+        self.book_side(side).price_to_level.get(&price)
+    }
+
+    /// Every level on `side` with its price, from the best price on.
+    pub fn levels(&self, side: Side) -> impl Iterator<Item = (u64, &Level)> {
+        self.book_side(side).levels().map(|(&price, level)| (price, level))
+    }
+
+    /// Same as `levels`, keeping to the levels priced within `prices`.
+    pub fn levels_in_range(&self, side: Side, prices: impl RangeBounds<u64>) -> impl Iterator<Item = (u64, &Level)> {
+        self.book_side(side).levels_in(prices).map(|(&price, level)| (price, level))
+    }
+
+    /// Orders resting at `price` on `side` in time priority, none if nothing
+    /// rests there.
+    pub fn orders_at(&self, side: Side, price: u64) -> impl Iterator<Item = &Order> {
+        self.level(side, price).into_iter().flat_map(Level::orders)
+    }
+
+    /// Shown quantity resting at `price` on `side`, iceberg reserves left out.
+    pub fn depth_at(&self, side: Side, price: u64) -> u128 {
+        self.level(side, price).map_or(0, Level::quantity)
+    }
+
+    /// Shown quantity across every level on `side`, iceberg reserves left out.
+    pub fn total_quantity(&self, side: Side) -> u128 {
+        self.book_side(side).price_to_level.values().map(Level::quantity).sum()
+    }
+
+    /// Number of resting orders across both sides.
+    pub fn order_count(&self) -> usize {
+        self.order_id_to_location.len()
+    }
+
+    /// Best resting price on `side`, the highest bid or the lowest ask.
+    pub fn best_price(&self, side: Side) -> Option<u64> {
+        self.book_side(side).best_price()
+    }
+
+    /// Price of the most recent trade, what a stop coming in is checked
+    /// against. Stops already waiting are checked against every trade.
+    pub fn last_price(&self) -> Option<u64> {
+        self.last_price
+    }
+
+    /// Number of stops waiting off the book for their trigger.
+    pub fn stop_count(&self) -> usize {
+        self.stop_id_to_trigger.len()
+    }
+}
+
+// What `amount` costs at `price`. Costs are kept wide enough that no sweep
+// of a `u64` amount can overflow them, whatever the prices.
+fn notional(price: u64, amount: u64) -> u128 {
+    u128::from(price) * u128::from(amount)
+}
+
+// Takes the entries keyed up to and including `key` out of `map`
+fn up_to<V>(map: &mut BTreeMap<u64, V>, key: u64) -> BTreeMap<u64, V> {
+    match key.checked_add(1) {
+        Some(above) => {
+            let above = map.split_off(&above);
+            std::mem::replace(map, above)
+        },
+        None => std::mem::take(map),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::clock::ManualClock;
+    use crate::policy::{ProRata, TopOrderProRata};
+
+    #[test]
+    fn test_add_remove_take() {
+        let mut order_book = OrderBook::new();
+
+        order_book.add(Side::Sell, 1137, 100).unwrap();
+        order_book.add(Side::Sell, 1130, 10).unwrap();
+        order_book.add(Side::Sell, 1130, 50).unwrap();
+        order_book.remove_at(Side::Sell, 0).unwrap();
+        order_book.add(Side::Sell, 1150, 200).unwrap();
+
+        assert_eq!(3, order_book.level_count(Side::Sell));
+        assert_eq!(3, order_book.order_count());
+        assert_eq!(Some(1130), order_book.best_price(Side::Sell));
+
+        assert_eq!(50 * 1130 + 100 * 1137 + 50 * 1150, order_book.take(Side::Buy, 200, TakeMode::Partial).unwrap().cost);
+
+        assert_eq!(1, order_book.order_count());
+        assert_eq!(Some(1150), order_book.best_price(Side::Sell));
+    }
+
+    #[test]
+    fn test_remove_at_crosses_levels() {
+        let mut order_book = OrderBook::new();
+
+        order_book.add(Side::Sell, 10, 1).unwrap();
+        order_book.add(Side::Sell, 10, 2).unwrap();
+        order_book.add(Side::Sell, 20, 3).unwrap();
+        order_book.add(Side::Sell, 30, 4).unwrap();
+
+        // Index 2 is the only order at 20, so the level goes with it
+        order_book.remove_at(Side::Sell, 2).unwrap();
+        assert_eq!(2, order_book.level_count(Side::Sell));
+
+        assert_eq!(10 + 2 * 10 + 4 * 30, order_book.take(Side::Buy, 7, TakeMode::Partial).unwrap().cost);
+        assert!(order_book.is_empty());
+    }
+
+    #[test]
+    fn test_take_partially_fills_front_order() {
+        let mut order_book = OrderBook::new();
+
+        order_book.add(Side::Sell, 5, 10).unwrap();
+        order_book.add(Side::Sell, 5, 10).unwrap();
+
+        assert_eq!(5 * 15, order_book.take(Side::Buy, 15, TakeMode::Partial).unwrap().cost);
+        assert_eq!(1, order_book.order_count());
+        assert_eq!(5 * 5, order_book.take(Side::Buy, 5, TakeMode::Partial).unwrap().cost);
+        assert!(order_book.is_empty());
+    }
+
+    #[test]
+    fn test_cancel_by_id() {
+        let mut order_book = OrderBook::new();
+
+        let first = order_book.add(Side::Sell, 10, 1).unwrap().id;
+        let second = order_book.add(Side::Sell, 10, 2).unwrap().id;
+        let third = order_book.add(Side::Sell, 20, 3).unwrap().id;
+
+        // Orders ahead of `second` come and go, its id still finds it
+        order_book.remove_at(Side::Sell, 0).unwrap();
+        assert_eq!(first + 3, order_book.add(Side::Sell, 5, 4).unwrap().id);
+
+        assert_eq!(2, order_book.cancel(second).unwrap().amount);
+        assert_eq!(Err(Error::UnknownOrder { id: second }), order_book.cancel(second));
+        assert_eq!(Err(Error::UnknownOrder { id: first }), order_book.cancel(first));
+
+        // `third` is partially filled and keeps its id
+        assert_eq!(4 * 5 + 20, order_book.take(Side::Buy, 5, TakeMode::Partial).unwrap().cost);
+        assert_eq!(2, order_book.cancel(third).unwrap().amount);
+        assert!(order_book.is_empty());
+        assert_eq!(0, order_book.order_count());
+    }
+
+    #[test]
+    fn test_crossing_limit_order_rests_remainder() {
+        let mut order_book = OrderBook::new();
+
+        order_book.add(Side::Sell, 101, 5).unwrap();
+        order_book.add(Side::Sell, 102, 5).unwrap();
+        order_book.add(Side::Sell, 104, 5).unwrap();
+
+        // Trades through 101 and 102, stops short of 104 and rests the rest as a bid
+        let added = order_book.add(Side::Buy, 103, 12).unwrap();
+        assert_eq!(
+            Added { id: 3, filled: 10, cost: 5 * 101 + 5 * 102, resting: 2, cancelled: 0 },
+            added
+        );
+        assert_eq!(Some(103), order_book.best_price(Side::Buy));
+        assert_eq!(Some(104), order_book.best_price(Side::Sell));
+
+        // Fully matched orders never rest
+        let added = order_book.add(Side::Sell, 100, 2).unwrap();
+        assert_eq!(Added { id: 4, filled: 2, cost: 2 * 103, resting: 0, cancelled: 0 }, added);
+        assert!(order_book.cancel(added.id).is_err());
+        assert_eq!(None, order_book.best_price(Side::Buy));
+        assert_eq!(1, order_book.order_count());
+    }
+
+    #[test]
+    fn test_bids_are_taken_from_highest_price() {
+        let mut order_book = OrderBook::new();
+
+        order_book.add(Side::Buy, 90, 1).unwrap();
+        order_book.add(Side::Buy, 95, 2).unwrap();
+        order_book.add(Side::Buy, 95, 3).unwrap();
+        order_book.add(Side::Buy, 80, 4).unwrap();
+
+        // Positions count from the best bid down
+        assert_eq!(3, order_book.remove_at(Side::Buy, 1).unwrap().amount);
+
+        assert_eq!(2 * 95 + 90 + 80, order_book.take(Side::Sell, 4, TakeMode::Partial).unwrap().cost);
+        assert_eq!(Some(80), order_book.best_price(Side::Buy));
+        assert_eq!(1, order_book.level_count(Side::Buy));
+        assert_eq!(0, order_book.level_count(Side::Sell));
+    }
+
+    #[test]
+    fn test_fill_reports() {
+        let mut order_book = OrderBook::new();
+
+        let first = order_book.add(Side::Sell, 10, 3).unwrap().id;
+        let second = order_book.add(Side::Sell, 10, 4).unwrap().id;
+        let third = order_book.add(Side::Sell, 11, 5).unwrap().id;
+
+        let mut fills = Vec::new();
+        assert_eq!(7 * 10 + 2 * 11, order_book.take_into(Side::Buy, 9, TakeMode::Partial, &mut fills).unwrap().cost);
+        assert_eq!(
+            vec![
+                Fill { maker_id: first, price: 10, amount: 3, maker_remaining: 0 },
+                Fill { maker_id: second, price: 10, amount: 4, maker_remaining: 0 },
+                Fill { maker_id: third, price: 11, amount: 2, maker_remaining: 3 },
+            ],
+            fills
+        );
+        assert!(fills[1].is_maker_done());
+        assert!(!fills[2].is_maker_done());
+
+        // A crossing limit order reports the same way
+        fills.clear();
+        let added = order_book.add_into(Side::Buy, 12, 5, &mut fills).unwrap();
+        assert_eq!(2, added.resting);
+        assert_eq!(vec![Fill { maker_id: third, price: 11, amount: 3, maker_remaining: 0 }], fills);
+    }
+
+    #[test]
+    fn test_take_more_than_the_book_holds() {
+        let mut order_book = OrderBook::new();
+
+        order_book.add(Side::Sell, 10, 3).unwrap();
+        order_book.add(Side::Sell, 11, 4).unwrap();
+
+        // Fill-or-kill leaves the book alone
+        assert_eq!(
+            Err(Error::Rejected { reason: Reject::NotEnoughLiquidity }),
+            order_book.take(Side::Buy, 10, TakeMode::FillOrKill)
+        );
+        assert_eq!(2, order_book.order_count());
+
+        assert_eq!(
+            Taken { filled: 7, cost: 3 * 10 + 4 * 11, unfilled: 0 },
+            order_book.take(Side::Buy, 7, TakeMode::FillOrKill).unwrap()
+        );
+
+        // Partial sweeps whatever is left, here nothing
+        order_book.add(Side::Sell, 12, 2).unwrap();
+        assert_eq!(
+            Taken { filled: 2, cost: 2 * 12, unfilled: 8 },
+            order_book.take(Side::Buy, 10, TakeMode::Partial).unwrap()
+        );
+        assert_eq!(
+            Taken { filled: 0, cost: 0, unfilled: 5 },
+            order_book.take(Side::Buy, 5, TakeMode::Partial).unwrap()
+        );
+        assert!(order_book.is_empty());
+    }
+
+    #[test]
+    fn test_bad_commands_are_rejected() {
+        let mut order_book = OrderBook::new();
+
+        assert_eq!(Err(Error::ZeroAmount), order_book.add(Side::Sell, 10, 0));
+        assert_eq!(Err(Error::ZeroAmount), order_book.take(Side::Buy, 0, TakeMode::Partial));
+        assert_eq!(Err(Error::IndexOutOfRange { index: 0 }), order_book.remove_at(Side::Sell, 0));
+
+        order_book.add(Side::Sell, 10, 1).unwrap();
+        order_book.add(Side::Sell, 11, 1).unwrap();
+        assert_eq!(Err(Error::IndexOutOfRange { index: 2 }), order_book.remove_at(Side::Sell, 2));
+        assert_eq!(Err(Error::IndexOutOfRange { index: 0 }), order_book.remove_at(Side::Buy, 0));
+
+        // The book is still there to keep trading on
+        assert_eq!(2, order_book.order_count());
+        assert!(order_book.apply(Command::Cancel { id: 7 }).is_err());
+        assert!(order_book.apply(Command::Take { side: Side::Buy, amount: 2, limit: None, mode: TakeMode::Partial }).is_ok());
+        assert!(order_book.is_empty());
+    }
+
+    #[test]
+    fn test_level_totals() {
+        let mut order_book = OrderBook::new();
+
+        let first = order_book.add(Side::Sell, 10, 3).unwrap().id;
+        order_book.add(Side::Sell, 10, 4).unwrap();
+        order_book.add(Side::Sell, 10, 5).unwrap();
+        order_book.add(Side::Sell, 11, 6).unwrap();
+
+        let level = order_book.level(Side::Sell, 10).unwrap();
+        assert_eq!((12, 3), (level.quantity(), level.order_count()));
+
+        order_book.cancel(first).unwrap();
+        order_book.take(Side::Buy, 2, TakeMode::Partial).unwrap();
+        let level = order_book.level(Side::Sell, 10).unwrap();
+        assert_eq!((7, 2), (level.quantity(), level.order_count()));
+
+        order_book.remove_at(Side::Sell, 1).unwrap();
+        let level = order_book.level(Side::Sell, 10).unwrap();
+        assert_eq!((2, 1), (level.quantity(), level.order_count()));
+
+        order_book.add(Side::Buy, 9, 1).unwrap();
+        assert_eq!(1, order_book.level(Side::Buy, 9).unwrap().quantity());
+        assert!(order_book.level(Side::Sell, 9).is_none());
+    }
+
+    #[test]
+    fn test_level_totals_past_u64() {
+        let mut order_book = OrderBook::new();
+        order_book.add(Side::Sell, 10, u64::MAX).unwrap();
+        order_book.add(Side::Sell, 10, u64::MAX).unwrap();
+        order_book.add(Side::Sell, 11, 1).unwrap();
+
+        let twice = 2 * u128::from(u64::MAX);
+        assert_eq!(twice, order_book.depth_at(Side::Sell, 10));
+        assert_eq!(twice + 1, order_book.total_quantity(Side::Sell));
+
+        // Part of the level, then everything past the first order
+        let taken = order_book.take(Side::Buy, u64::MAX - 1, TakeMode::FillOrKill).unwrap();
+        assert_eq!((u64::MAX - 1, 0), (taken.filled, taken.unfilled));
+        assert_eq!(twice - u128::from(u64::MAX - 1), order_book.depth_at(Side::Sell, 10));
+
+        let taken = order_book.take(Side::Buy, u64::MAX, TakeMode::Partial).unwrap();
+        assert_eq!((u64::MAX, 0), (taken.filled, taken.unfilled));
+        assert_eq!(1, order_book.depth_at(Side::Sell, 10));
+        assert_eq!(2, order_book.total_quantity(Side::Sell));
+    }
+
+    #[test]
+    fn test_sweep_through_whole_levels() {
+        let mut order_book = OrderBook::new();
+
+        for price in 100..200 {
+            order_book.add(Side::Sell, price, 2).unwrap();
+            order_book.add(Side::Sell, price, 3).unwrap();
+        }
+
+        let mut fills = Vec::new();
+        let taken = order_book.take_into(Side::Buy, 5 * 99 + 4, TakeMode::Partial, &mut fills).unwrap();
+        assert_eq!((100..199).map(|price| price * 5).sum::<u128>() + 199 * 4, taken.cost);
+        assert_eq!(200, fills.len());
+        assert!(fills[..199].iter().all(Fill::is_maker_done));
+        assert_eq!(1, fills[199].maker_remaining);
+
+        let level = order_book.level(Side::Sell, 199).unwrap();
+        assert_eq!((1, 1), (level.quantity(), level.order_count()));
+        assert_eq!(1, order_book.level_count(Side::Sell));
+    }
+
+    #[test]
+    fn test_take_up_to_price() {
+        let mut order_book = OrderBook::new();
+
+        order_book.add(Side::Sell, 10, 3).unwrap();
+        order_book.add(Side::Sell, 11, 4).unwrap();
+        order_book.add(Side::Sell, 12, 5).unwrap();
+        order_book.add(Side::Buy, 8, 5).unwrap();
+
+        // Stops at 11 and leaves the rest unfilled rather than resting it
+        assert_eq!(
+            Taken { filled: 7, cost: 3 * 10 + 4 * 11, unfilled: 3 },
+            order_book.take_up_to_price(Side::Buy, 10, 11, TakeMode::Partial).unwrap()
+        );
+        assert_eq!(Some(12), order_book.best_price(Side::Sell));
+        assert_eq!(Some(8), order_book.best_price(Side::Buy));
+
+        // Fill-or-kill only counts liquidity inside the limit
+        assert_eq!(
+            Err(Error::Rejected { reason: Reject::NotEnoughLiquidity }),
+            order_book.take_up_to_price(Side::Sell, 5, 9, TakeMode::FillOrKill)
+        );
+        assert_eq!(
+            Taken { filled: 5, cost: 5 * 8, unfilled: 0 },
+            order_book.take_up_to_price(Side::Sell, 5, 8, TakeMode::FillOrKill).unwrap()
+        );
+        assert_eq!(None, order_book.best_price(Side::Buy));
+    }
+
+    #[test]
+    fn test_quote_leaves_book_alone() {
+        let mut order_book = OrderBook::new();
+
+        order_book.add(Side::Sell, 10, 3).unwrap();
+        order_book.add(Side::Sell, 10, 1).unwrap();
+        order_book.add(Side::Sell, 12, 4).unwrap();
+        order_book.add(Side::Sell, 15, 2).unwrap();
+
+        let quote = order_book.quote(Side::Buy, 6);
+        assert_eq!(Quote { filled: 6, cost: 4 * 10 + 2 * 12, worst_price: Some(12), levels: 2 }, quote);
+        assert_eq!(Some(64.0 / 6.0), quote.average_price());
+
+        // Same answer twice, and the take that follows agrees with it
+        assert_eq!(quote, order_book.quote(Side::Buy, 6));
+        assert_eq!(quote.cost, order_book.take(Side::Buy, 6, TakeMode::Partial).unwrap().cost);
+
+        let quote = order_book.quote(Side::Buy, 100);
+        assert_eq!(Quote { filled: 4, cost: 2 * 12 + 2 * 15, worst_price: Some(15), levels: 2 }, quote);
+
+        let quote = order_book.quote(Side::Sell, 1);
+        assert_eq!(Quote { filled: 0, cost: 0, worst_price: None, levels: 0 }, quote);
+        assert_eq!(None, quote.average_price());
+    }
+
+    #[test]
+    fn test_cost_past_u64() {
+        let mut order_book = OrderBook::new();
+        let price = i64::MAX as u64;
+
+        order_book.add(Side::Sell, price, 3).unwrap();
+        order_book.add(Side::Sell, u64::MAX, 1).unwrap();
+
+        let cost = 3 * u128::from(price) + u128::from(u64::MAX);
+        assert_eq!(cost, order_book.quote(Side::Buy, 4).cost);
+        assert_eq!(
+            Taken { filled: 4, cost, unfilled: 0 },
+            order_book.take(Side::Buy, 4, TakeMode::Partial).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_take_notional() {
+        let mut order_book = OrderBook::new();
+
+        order_book.add(Side::Sell, 10, 3).unwrap();
+        order_book.add(Side::Sell, 12, 4).unwrap();
+        order_book.add(Side::Sell, 15, 2).unwrap();
+
+        // 30 buys out 10, the other 35 buys 2 at 12 with 11 to spare, not enough for a third
+        let quote = order_book.quote_notional(Side::Buy, 65);
+        assert_eq!(Quote { filled: 5, cost: 54, worst_price: Some(12), levels: 2 }, quote);
+        assert_eq!(3, order_book.order_count());
+
+        let mut fills = Vec::new();
+        assert_eq!(Spent { filled: 5, cost: 54 }, order_book.take_notional_into(Side::Buy, 65, &mut fills).unwrap());
+        assert_eq!(2, fills.len());
+        assert_eq!(2, order_book.level(Side::Sell, 12).unwrap().quantity());
+
+        // Too small a budget for a single unit buys nothing
+        assert_eq!(Spent { filled: 0, cost: 0 }, order_book.take_notional(Side::Buy, 11).unwrap());
+
+        // A budget beyond the book buys it all
+        assert_eq!(Spent { filled: 4, cost: 2 * 12 + 2 * 15 }, order_book.take_notional(Side::Buy, 1000).unwrap());
+        assert!(order_book.is_empty());
+        assert_eq!(Err(Error::ZeroAmount), order_book.take_notional(Side::Buy, 0));
+    }
+
+    #[test]
+    fn test_take_notional_past_all_or_none() {
+        let mut order_book = OrderBook::new();
+        order_book.add(Side::Sell, 10, 3).unwrap();
+        order_book.submit(NewOrder { all_or_none: true, ..NewOrder::limit(Side::Sell, 10, 5) }).unwrap();
+        order_book.add(Side::Sell, 11, 10).unwrap();
+
+        // 30 buys the 3 at 10, too little is left for the all-or-none 5 there
+        // but enough for 2 at 11
+        let quote = order_book.quote_notional(Side::Buy, 60);
+        assert_eq!(Quote { filled: 5, cost: 52, worst_price: Some(11), levels: 2 }, quote);
+        assert_eq!(Spent { filled: 5, cost: 52 }, order_book.take_notional(Side::Buy, 60).unwrap());
+        assert_eq!((5, 8), (order_book.depth_at(Side::Sell, 10), order_book.depth_at(Side::Sell, 11)));
+    }
+
+    #[test]
+    fn test_amend_down_keeps_priority() {
+        let mut order_book = OrderBook::new();
+
+        let first = order_book.add(Side::Sell, 10, 5).unwrap().id;
+        let second = order_book.add(Side::Sell, 10, 5).unwrap().id;
+
+        assert_eq!(Added { id: first, filled: 0, cost: 0, resting: 2, cancelled: 0 }, order_book.amend(first, 10, 2).unwrap());
+        assert_eq!(7, order_book.level(Side::Sell, 10).unwrap().quantity());
+
+        let mut fills = Vec::new();
+        order_book.take_into(Side::Buy, 3, TakeMode::Partial, &mut fills).unwrap();
+        assert_eq!(vec![first, second], fills.iter().map(|fill| fill.maker_id).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_amend_up_or_away_loses_priority() {
+        let mut order_book = OrderBook::new();
+
+        let first = order_book.add(Side::Sell, 10, 5).unwrap().id;
+        let second = order_book.add(Side::Sell, 10, 5).unwrap().id;
+        let third = order_book.add(Side::Sell, 11, 5).unwrap().id;
+
+        // Bigger goes to the back of the same level
+        order_book.amend(first, 10, 6).unwrap();
+        assert_eq!(11, order_book.level(Side::Sell, 10).unwrap().quantity());
+
+        // A new price goes to the back of that level
+        order_book.amend(second, 11, 5).unwrap();
+        assert_eq!(10, order_book.level(Side::Sell, 11).unwrap().quantity());
+
+        let mut fills = Vec::new();
+        order_book.take_into(Side::Buy, 15, TakeMode::Partial, &mut fills).unwrap();
+        assert_eq!(vec![first, third, second], fills.iter().map(|fill| fill.maker_id).collect::<Vec<_>>());
+
+        // The id survives both moves and still cancels what is left of it
+        assert_eq!(1, order_book.cancel(second).unwrap().amount);
+        assert!(order_book.is_empty());
+        assert_eq!(Err(Error::UnknownOrder { id: first }), order_book.amend(first, 10, 1));
+    }
+
+    #[test]
+    fn test_amend_across_the_spread_trades() {
+        let mut order_book = OrderBook::new();
+
+        order_book.add(Side::Sell, 12, 3).unwrap();
+        let bid = order_book.add(Side::Buy, 10, 5).unwrap().id;
+
+        assert_eq!(Added { id: bid, filled: 3, cost: 3 * 12, resting: 2, cancelled: 0 }, order_book.amend(bid, 12, 5).unwrap());
+        assert_eq!(Some(12), order_book.best_price(Side::Buy));
+        assert_eq!(None, order_book.best_price(Side::Sell));
+        assert!(order_book.level(Side::Buy, 10).is_none());
+    }
+
+
+    #[test]
+    fn test_time_in_force() {
+        let mut order_book = OrderBook::new();
+
+        order_book.add(Side::Sell, 10, 3).unwrap();
+        order_book.add(Side::Sell, 11, 3).unwrap();
+
+        // Immediate-or-cancel trades what crosses and drops the rest
+        let ioc = NewOrder { time_in_force: TimeInForce::ImmediateOrCancel, ..NewOrder::limit(Side::Buy, 10, 5) };
+        assert_eq!(Added { id: 2, filled: 3, cost: 3 * 10, resting: 0, cancelled: 2 }, order_book.submit(ioc).unwrap());
+        assert_eq!(None, order_book.best_price(Side::Buy));
+
+        // Fill-or-kill goes through only if it fills in full
+        let fok = NewOrder { time_in_force: TimeInForce::FillOrKill, ..NewOrder::limit(Side::Buy, 11, 4) };
+        assert_eq!(Err(Error::Rejected { reason: Reject::NotEnoughLiquidity }), order_book.submit(fok));
+        assert_eq!(3, order_book.level(Side::Sell, 11).unwrap().quantity());
+
+        let fok = NewOrder { amount: 2, ..fok };
+        assert_eq!(Added { id: 3, filled: 2, cost: 2 * 11, resting: 0, cancelled: 0 }, order_book.submit(fok).unwrap());
+
+        // Post-only rests only if it would not trade
+        let post = NewOrder { time_in_force: TimeInForce::PostOnly, ..NewOrder::limit(Side::Buy, 11, 4) };
+        assert_eq!(Err(Error::Rejected { reason: Reject::WouldCross }), order_book.submit(post));
+
+        let post = NewOrder { price: 10, ..post };
+        assert_eq!(Added { id: 4, filled: 0, cost: 0, resting: 4, cancelled: 0 }, order_book.submit(post).unwrap());
+
+        // Good till cancel trades and rests the remainder
+        let gtc = NewOrder::limit(Side::Sell, 10, 6);
+        assert_eq!(Added { id: 5, filled: 4, cost: 4 * 10, resting: 2, cancelled: 0 }, order_book.submit(gtc).unwrap());
+        assert_eq!(Some(10), order_book.best_price(Side::Sell));
+        assert_eq!(2, order_book.order_count());
+    }
+
+    #[test]
+    fn test_iceberg_refills_at_the_back() {
+        let mut order_book = OrderBook::new();
+
+        let iceberg = order_book.submit(NewOrder { peak: Some(3), ..NewOrder::limit(Side::Sell, 10, 8) }).unwrap();
+        let plain = order_book.add(Side::Sell, 10, 2).unwrap().id;
+        assert_eq!(8, iceberg.resting);
+
+        // Only the peak shows
+        assert_eq!(5, order_book.level(Side::Sell, 10).unwrap().quantity());
+        assert_eq!(Some(10), order_book.quote(Side::Buy, 10).worst_price);
+
+        let mut fills = Vec::new();
+        order_book.take_into(Side::Buy, 6, TakeMode::Partial, &mut fills).unwrap();
+        assert_eq!(
+            vec![
+                Fill { maker_id: iceberg.id, price: 10, amount: 3, maker_remaining: 5 },
+                Fill { maker_id: plain, price: 10, amount: 2, maker_remaining: 0 },
+                Fill { maker_id: iceberg.id, price: 10, amount: 1, maker_remaining: 4 },
+            ],
+            fills
+        );
+        assert_eq!(2, order_book.level(Side::Sell, 10).unwrap().quantity());
+
+        // A new order queues behind the refill, then the next refill behind it
+        let late = order_book.add(Side::Sell, 10, 1).unwrap().id;
+        fills.clear();
+        order_book.take_into(Side::Buy, 4, TakeMode::Partial, &mut fills).unwrap();
+        assert_eq!(
+            vec![(iceberg.id, 2), (late, 1), (iceberg.id, 1)],
+            fills.iter().map(|fill| (fill.maker_id, fill.amount)).collect::<Vec<_>>()
+        );
+
+        let order = order_book.cancel(iceberg.id).unwrap();
+        assert_eq!((1, 0), (order.amount, order.hidden()));
+        assert!(order_book.is_empty());
+    }
+
+    #[test]
+    fn test_iceberg_is_taken_in_full() {
+        let mut order_book = OrderBook::new();
+
+        order_book.submit(NewOrder { peak: Some(2), ..NewOrder::limit(Side::Sell, 10, 7) }).unwrap();
+        order_book.add(Side::Sell, 11, 1).unwrap();
+
+        // The reserve counts for fill-or-kill and for a notional budget
+        assert_eq!(Taken { filled: 7, cost: 70, unfilled: 0 }, order_book.take(Side::Buy, 7, TakeMode::FillOrKill).unwrap());
+        assert_eq!(Some(11), order_book.best_price(Side::Sell));
+
+        let iceberg = order_book.submit(NewOrder { peak: Some(2), ..NewOrder::limit(Side::Sell, 11, 5) }).unwrap().id;
+        assert_eq!(Spent { filled: 4, cost: 44 }, order_book.take_notional(Side::Buy, 50).unwrap());
+
+        // Cutting the amount takes from the reserve first and keeps priority
+        order_book.amend(iceberg, 11, 1).unwrap();
+        assert_eq!(1, order_book.level(Side::Sell, 11).unwrap().quantity());
+        assert_eq!(Err(Error::ZeroAmount), order_book.submit(NewOrder { peak: Some(0), ..NewOrder::limit(Side::Buy, 1, 1) }));
+    }
+
+    #[test]
+    fn test_pro_rata_books() {
+        let mut order_book = OrderBook::with_policy(ProRata::default());
+        let small = order_book.add(Side::Sell, 10, 10).unwrap().id;
+        let big = order_book.add(Side::Sell, 10, 20).unwrap().id;
+        let last = order_book.add(Side::Sell, 10, 10).unwrap().id;
+
+        let mut fills = Vec::new();
+        order_book.take_into(Side::Buy, 10, TakeMode::Partial, &mut fills).unwrap();
+        assert_eq!(
+            vec![
+                Fill { maker_id: small, price: 10, amount: 3, maker_remaining: 7 },
+                Fill { maker_id: big, price: 10, amount: 5, maker_remaining: 15 },
+                Fill { maker_id: last, price: 10, amount: 2, maker_remaining: 8 },
+            ],
+            fills
+        );
+        assert_eq!(30, order_book.level(Side::Sell, 10).unwrap().quantity());
+
+        // Past the level every policy fills in full and moves on
+        order_book.add(Side::Sell, 11, 5).unwrap();
+        assert_eq!(30 * 10 + 11, order_book.take(Side::Buy, 31, TakeMode::Partial).unwrap().cost);
+
+        let mut order_book = OrderBook::with_policy(TopOrderProRata::default());
+        let top = order_book.add(Side::Buy, 10, 4).unwrap().id;
+        order_book.add(Side::Buy, 10, 10).unwrap();
+        order_book.add(Side::Buy, 10, 10).unwrap();
+
+        fills.clear();
+        order_book.take_into(Side::Sell, 12, TakeMode::Partial, &mut fills).unwrap();
+        assert_eq!(vec![4, 4, 4], fills.iter().map(|fill| fill.amount).collect::<Vec<_>>());
+        assert_eq!(Err(Error::UnknownOrder { id: top }), order_book.cancel(top));
+        assert_eq!(2, order_book.order_count());
+    }
+
+    // Newest first, so used up orders sit at the back of the level
+    struct Lifo;
+
+    impl MatchingPolicy for Lifo {
+        fn allocate(&self, level: &Level, mut amount: u64, allocations: &mut Vec<(usize, u64)>) {
+            for (index, order) in level.orders().enumerate().rev() {
+                if amount == 0 {
+                    break;
+                }
+
+                let share = order.amount.min(amount);
+                allocations.push((index, share));
+                amount -= share;
+            }
+            allocations.reverse();
+        }
+    }
+
+    #[test]
+    fn test_custom_policy() {
+        let mut order_book = OrderBook::with_policy(Lifo);
+        let first = order_book.add(Side::Sell, 10, 5).unwrap().id;
+        let iceberg = order_book.submit(NewOrder { peak: Some(2), ..NewOrder::limit(Side::Sell, 10, 6) }).unwrap().id;
+        let last = order_book.add(Side::Sell, 10, 3).unwrap().id;
+
+        // The iceberg refills behind what is left of the first order
+        let mut fills = Vec::new();
+        order_book.take_into(Side::Buy, 6, TakeMode::Partial, &mut fills).unwrap();
+        assert_eq!(
+            vec![(first, 1), (iceberg, 2), (last, 3)],
+            fills.iter().map(|fill| (fill.maker_id, fill.amount)).collect::<Vec<_>>()
+        );
+        assert_eq!(
+            vec![(first, 4), (iceberg, 2)],
+            order_book.level(Side::Sell, 10).unwrap().orders().map(|order| (order.id, order.amount)).collect::<Vec<_>>()
+        );
+        assert_eq!(Err(Error::UnknownOrder { id: last }), order_book.cancel(last));
+        assert_eq!(iceberg, order_book.cancel(iceberg).unwrap().id);
+    }
+
+    #[test]
+    fn test_stops_wait_for_a_trade() {
+        let mut order_book = OrderBook::new();
+
+        order_book.add(Side::Sell, 10, 5).unwrap();
+        order_book.add(Side::Sell, 12, 5).unwrap();
+
+        let stop = order_book.submit_stop(Stop { side: Side::Buy, trigger: 10, amount: 3, limit: None, owner: None }).unwrap();
+        assert_eq!(1, order_book.stop_count());
+        assert_eq!(5, order_book.level(Side::Sell, 10).unwrap().quantity());
+
+        // A trade at the trigger sends the stop in as a market order
+        let mut fills = Vec::new();
+        order_book.take_into(Side::Buy, 1, TakeMode::Partial, &mut fills).unwrap();
+        assert_eq!(vec![(10, 1), (10, 3)], fills.iter().map(|fill| (fill.price, fill.amount)).collect::<Vec<_>>());
+        assert_eq!(0, order_book.stop_count());
+        assert_eq!(Err(Error::UnknownOrder { id: stop }), order_book.cancel(stop));
+
+        // A stop-limit rests what its limit does not reach
+        let stop = order_book.submit_stop(Stop { side: Side::Buy, trigger: 11, amount: 6, limit: Some(12), owner: None }).unwrap();
+        order_book.take(Side::Buy, 2, TakeMode::Partial).unwrap();
+        assert_eq!(Some(12), order_book.last_price());
+        assert_eq!(Some(12), order_book.best_price(Side::Buy));
+        assert_eq!(None, order_book.best_price(Side::Sell));
+        assert_eq!(2, order_book.cancel(stop).unwrap().amount);
+
+        // Cancelled before it triggers
+        let stop = order_book.submit_stop(Stop { side: Side::Sell, trigger: 5, amount: 1, limit: None, owner: None }).unwrap();
+        assert_eq!(1, order_book.cancel(stop).unwrap().amount);
+        assert_eq!(0, order_book.stop_count());
+    }
+
+    #[test]
+    fn test_stop_chain_is_deterministic() {
+        let mut order_book = OrderBook::new();
+
+        for price in 20..25 {
+            order_book.add(Side::Buy, price, 2).unwrap();
+        }
+
+        // Submitted out of trigger order, set off in submission order
+        let low = order_book.submit_stop(Stop { side: Side::Sell, trigger: 22, amount: 2, limit: None, owner: None }).unwrap();
+        order_book.submit_stop(Stop { side: Side::Sell, trigger: 23, amount: 2, limit: None, owner: None }).unwrap();
+        order_book.submit_stop(Stop { side: Side::Sell, trigger: 24, amount: 2, limit: None, owner: None }).unwrap();
+        order_book.submit_stop(Stop { side: Side::Sell, trigger: 10, amount: 2, limit: None, owner: None }).unwrap();
+
+        // Selling 4 trades down to 23, which sets off the stops at 23 and 24.
+        // The first of them reaches 22 and sets off `low`, which goes in
+        // after the stop at 24 and takes the last bid.
+        let mut fills = Vec::new();
+        order_book.take_into(Side::Sell, 4, TakeMode::Partial, &mut fills).unwrap();
+        assert_eq!(
+            vec![24, 23, 22, 21, 20],
+            fills.iter().map(|fill| fill.price).collect::<Vec<_>>()
+        );
+        assert!(order_book.is_empty());
+        assert_eq!(Some(20), order_book.last_price());
+        assert_eq!(1, order_book.stop_count());
+        assert_eq!(Err(Error::UnknownOrder { id: low }), order_book.cancel(low));
+    }
+
+    #[test]
+    fn test_stops_see_every_price_a_sweep_trades_at() {
+        let mut order_book = OrderBook::new();
+        order_book.add(Side::Sell, 13, 1).unwrap();
+        order_book.take(Side::Buy, 1, TakeMode::Partial).unwrap();
+        order_book.submit_stop(Stop { side: Side::Sell, trigger: 12, amount: 1, limit: None, owner: None }).unwrap();
+
+        order_book.add(Side::Buy, 5, 1).unwrap();
+        order_book.add(Side::Sell, 10, 1).unwrap();
+        order_book.add(Side::Sell, 15, 1).unwrap();
+        assert_eq!(1, order_book.stop_count());
+
+        // The sweep ends at 15, but traded through the trigger at 10 on the way
+        order_book.take(Side::Buy, 2, TakeMode::Partial).unwrap();
+        assert_eq!(0, order_book.stop_count());
+        assert_eq!(Some(5), order_book.last_price());
+        assert!(order_book.is_empty());
+    }
+
+    #[test]
+    fn test_pegged_orders_follow_the_top() {
+        let mut order_book = OrderBook::new();
+
+        let best_bid = Peg { reference: PegReference::BestBid, offset: 0 };
+        assert_eq!(
+            Err(Error::Rejected { reason: Reject::NoReference }),
+            order_book.submit(NewOrder::pegged(Side::Buy, best_bid, 5))
+        );
+
+        order_book.add(Side::Buy, 10, 1).unwrap();
+        let ask = order_book.add(Side::Sell, 14, 1).unwrap().id;
+
+        let bid_peg = order_book.submit(NewOrder::pegged(Side::Buy, best_bid, 5)).unwrap().id;
+        let above_ask = Peg { reference: PegReference::BestAsk, offset: 1 };
+        order_book.submit(NewOrder::pegged(Side::Sell, above_ask, 2)).unwrap();
+        let midpoint = Peg { reference: PegReference::Midpoint, offset: 0 };
+        order_book.submit(NewOrder::pegged(Side::Buy, midpoint, 3)).unwrap();
+
+        assert_eq!(6, order_book.level(Side::Buy, 10).unwrap().quantity());
+        assert_eq!(2, order_book.level(Side::Sell, 15).unwrap().quantity());
+        assert_eq!(3, order_book.level(Side::Buy, 12).unwrap().quantity());
+
+        // A better bid moves the bid peg up behind it, the midpoint of 11 and
+        // 14 still rounds down to 12
+        let bid = order_book.add(Side::Buy, 11, 1).unwrap().id;
+        assert_eq!(1, order_book.level(Side::Buy, 10).unwrap().quantity());
+        assert_eq!(6, order_book.level(Side::Buy, 11).unwrap().quantity());
+        assert_eq!(3, order_book.level(Side::Buy, 12).unwrap().quantity());
+
+        // Pegs never count as their own reference, so with the only priced
+        // ask gone the ask and midpoint pegs stay where they are
+        order_book.cancel(ask).unwrap();
+        assert_eq!(Some(15), order_book.best_price(Side::Sell));
+        assert_eq!(3, order_book.level(Side::Buy, 12).unwrap().quantity());
+
+        order_book.add(Side::Sell, 16, 1).unwrap();
+        assert_eq!(Some(16), order_book.best_price(Side::Sell));
+        assert_eq!(2, order_book.level(Side::Sell, 17).unwrap().quantity());
+        assert_eq!(3, order_book.level(Side::Buy, 13).unwrap().quantity());
+
+        // The peg queues behind the order it followed
+        let mut fills = Vec::new();
+        order_book.take_into(Side::Sell, 5, TakeMode::Partial, &mut fills).unwrap();
+        assert_eq!(
+            vec![(13, 3), (11, 1), (11, 1)],
+            fills.iter().map(|fill| (fill.price, fill.amount)).collect::<Vec<_>>()
+        );
+        assert_eq!(vec![bid, bid_peg], fills[1..].iter().map(|fill| fill.maker_id).collect::<Vec<_>>());
+
+        // With the plain bid at 11 gone the bid peg drops back to 10
+        assert_eq!(Some(10), order_book.best_price(Side::Buy));
+        assert_eq!(5, order_book.level(Side::Buy, 10).unwrap().quantity());
+    }
+
+    #[test]
+    fn test_fill_or_kill_against_own_orders() {
+        let modes = [
+            SelfTradePrevention::CancelResting,
+            SelfTradePrevention::CancelIncoming,
+            SelfTradePrevention::CancelBoth,
+            SelfTradePrevention::DecrementBoth,
+        ];
+        let fill_or_kill = |amount| NewOrder {
+            owner: Some(1),
+            time_in_force: TimeInForce::FillOrKill,
+            ..NewOrder::limit(Side::Buy, 10, amount)
+        };
+
+        for mode in modes {
+            // An ask at 9 from owner 2, then asks at 10 from owner 1 and owner 2
+            let mut order_book = OrderBook::new();
+            order_book.set_self_trade_prevention(mode);
+            for (price, owner) in [(9, 2), (10, 1), (10, 2)] {
+                order_book.submit(NewOrder { owner: Some(owner), ..NewOrder::limit(Side::Sell, price, 2) }).unwrap();
+            }
+
+            // Only the other owner's asks could ever trade
+            assert_eq!(
+                Err(Error::Rejected { reason: Reject::NotEnoughLiquidity }),
+                order_book.submit(fill_or_kill(5)),
+                "{:?}",
+                mode
+            );
+            assert_eq!(6, order_book.total_quantity(Side::Sell));
+
+            // Cancelling the own ask lets the sweep on to the ask behind it,
+            // any other mode stops the sweep there
+            if mode == SelfTradePrevention::CancelResting {
+                let added = order_book.submit(fill_or_kill(4)).unwrap();
+                assert_eq!(Added { id: 3, filled: 4, cost: 2 * 9 + 2 * 10, resting: 0, cancelled: 0 }, added);
+            } else {
+                assert!(order_book.submit(fill_or_kill(3)).is_err(), "{:?}", mode);
+                let added = order_book.submit(fill_or_kill(2)).unwrap();
+                assert_eq!(Added { id: 3, filled: 2, cost: 2 * 9, resting: 0, cancelled: 0 }, added);
+            }
+            assert_eq!(None, order_book.best_price(Side::Buy));
+        }
+    }
+
+    #[test]
+    fn test_fill_or_kill_reaches_orders_ahead_of_own() {
+        let modes = [
+            SelfTradePrevention::CancelResting,
+            SelfTradePrevention::CancelIncoming,
+            SelfTradePrevention::CancelBoth,
+            SelfTradePrevention::DecrementBoth,
+        ];
+        let buy = |amount, time_in_force| NewOrder {
+            owner: Some(1),
+            time_in_force,
+            ..NewOrder::limit(Side::Buy, 10, amount)
+        };
+
+        for mode in modes {
+            // Asks at 10 from owner 2, then owner 1
+            let mut order_book = OrderBook::new();
+            order_book.set_self_trade_prevention(mode);
+            for owner in [2, 1] {
+                order_book.submit(NewOrder { owner: Some(owner), ..NewOrder::limit(Side::Sell, 10, 2) }).unwrap();
+            }
+
+            assert_eq!(
+                Err(Error::Rejected { reason: Reject::NotEnoughLiquidity }),
+                order_book.submit(buy(3, TimeInForce::FillOrKill)),
+                "{:?}",
+                mode
+            );
+            let added = order_book.submit(buy(2, TimeInForce::FillOrKill)).unwrap();
+            assert_eq!(Added { id: 2, filled: 2, cost: 20, resting: 0, cancelled: 0 }, added, "{:?}", mode);
+        }
+
+        // Shared out pro rata, the ask ahead only gets part of the take before
+        // the sweep reaches the own ask
+        for mode in [SelfTradePrevention::CancelIncoming, SelfTradePrevention::DecrementBoth] {
+            let mut order_book = OrderBook::with_policy(ProRata { min_allocation: 0 });
+            order_book.set_self_trade_prevention(mode);
+            for owner in [2, 1] {
+                order_book.submit(NewOrder { owner: Some(owner), ..NewOrder::limit(Side::Sell, 10, 10) }).unwrap();
+            }
+
+            assert_eq!(
+                Err(Error::Rejected { reason: Reject::NotEnoughLiquidity }),
+                order_book.submit(buy(10, TimeInForce::FillOrKill)),
+                "{:?}",
+                mode
+            );
+            assert_eq!(20, order_book.total_quantity(Side::Sell));
+            let added = order_book.submit(buy(10, TimeInForce::ImmediateOrCancel)).unwrap();
+            assert_eq!((5, 5), (added.filled, added.cancelled), "{:?}", mode);
+        }
+    }
+
+    #[test]
+    fn test_peg_groups_let_go_of_orders_that_leave() {
+        let mut order_book = OrderBook::new();
+        order_book.add(Side::Sell, 12, 5).unwrap();
+        let below_ask = Peg { reference: PegReference::BestAsk, offset: -1 };
+        let pegged = NewOrder::pegged(Side::Buy, below_ask, 2);
+
+        // The reference never moves, so nothing is left for a move to clear up
+        let first = order_book.submit(pegged).unwrap().id;
+        let second = order_book.submit(pegged).unwrap().id;
+        order_book.take(Side::Sell, 3, TakeMode::Partial).unwrap();
+        assert_eq!(Err(Error::UnknownOrder { id: first }), order_book.cancel(first));
+        assert_eq!(vec![second], order_book.bids.peg_groups[&below_ask].ids);
+
+        order_book.cancel(second).unwrap();
+        assert!(order_book.bids.peg_groups.is_empty());
+
+        // A whole level taken in one step
+        order_book.submit(pegged).unwrap();
+        order_book.take(Side::Sell, 2, TakeMode::Partial).unwrap();
+        assert!(order_book.bids.peg_groups.is_empty());
+        assert_eq!(Some(12), order_book.best_price(Side::Sell));
+    }
+
+    #[test]
+    fn test_midpoint_of_the_highest_prices() {
+        let mut order_book = OrderBook::new();
+        order_book.add(Side::Buy, u64::MAX - 10, 1).unwrap();
+        order_book.add(Side::Sell, u64::MAX, 1).unwrap();
+
+        let below = Peg { reference: PegReference::Midpoint, offset: -1 };
+        let above = Peg { reference: PegReference::Midpoint, offset: 1 };
+        order_book.submit(NewOrder::pegged(Side::Buy, below, 1)).unwrap();
+        order_book.submit(NewOrder::pegged(Side::Sell, above, 1)).unwrap();
+        assert_eq!(1, order_book.depth_at(Side::Buy, u64::MAX - 6));
+        assert_eq!(1, order_book.depth_at(Side::Sell, u64::MAX - 4));
+
+        // An odd sum rounds away from the other side
+        order_book.add(Side::Buy, u64::MAX - 9, 1).unwrap();
+        assert_eq!(1, order_book.depth_at(Side::Buy, u64::MAX - 6));
+        assert_eq!(1, order_book.depth_at(Side::Sell, u64::MAX - 3));
+    }
+
+    // Asks at 10 from owner 1, owner 2, owner 1, then a buy from owner 1
+    fn self_trade(mode: SelfTradePrevention, amount: u64) -> (OrderBook, Added, Vec<Fill>) {
+        let mut order_book = OrderBook::new();
+        order_book.set_self_trade_prevention(mode);
+
+        for owner in [1, 2, 1] {
+            order_book.submit(NewOrder { owner: Some(owner), ..NewOrder::limit(Side::Sell, 10, 2) }).unwrap();
+        }
+
+        let mut fills = Vec::new();
+        let bid = NewOrder { owner: Some(1), ..NewOrder::limit(Side::Buy, 10, amount) };
+        let added = order_book.submit_into(bid, &mut fills).unwrap();
+
+        (order_book, added, fills)
+    }
+
+    #[test]
+    fn test_self_trade_prevention() {
+        // Both own asks go, the other one trades and the rest rests
+        let (order_book, added, fills) = self_trade(SelfTradePrevention::CancelResting, 3);
+        assert_eq!(Added { id: 3, filled: 2, cost: 20, resting: 1, cancelled: 0 }, added);
+        assert_eq!(vec![1], fills.iter().map(|fill| fill.maker_id).collect::<Vec<_>>());
+        assert_eq!(None, order_book.best_price(Side::Sell));
+
+        // The bid stops at the first own ask
+        let (order_book, added, fills) = self_trade(SelfTradePrevention::CancelIncoming, 3);
+        assert_eq!(Added { id: 3, filled: 0, cost: 0, resting: 0, cancelled: 3 }, added);
+        assert!(fills.is_empty());
+        assert_eq!(6, order_book.level(Side::Sell, 10).unwrap().quantity());
+
+        let (order_book, added, _) = self_trade(SelfTradePrevention::CancelBoth, 3);
+        assert_eq!(Added { id: 3, filled: 0, cost: 0, resting: 0, cancelled: 3 }, added);
+        assert_eq!(4, order_book.level(Side::Sell, 10).unwrap().quantity());
+
+        // 2 comes off the bid and the first ask, 2 trades with owner 2, then
+        // the last 1 comes off the bid and the second own ask
+        let (order_book, added, fills) = self_trade(SelfTradePrevention::DecrementBoth, 5);
+        assert_eq!(Added { id: 3, filled: 2, cost: 20, resting: 0, cancelled: 3 }, added);
+        assert_eq!(vec![(1, 2)], fills.iter().map(|fill| (fill.maker_id, fill.amount)).collect::<Vec<_>>());
+        assert_eq!(1, order_book.level(Side::Sell, 10).unwrap().quantity());
+
+        // Orders without an owner trade with anyone
+        let (mut order_book, _, _) = self_trade(SelfTradePrevention::CancelIncoming, 1);
+        assert_eq!(6, order_book.take(Side::Buy, 6, TakeMode::Partial).unwrap().filled);
+    }
+
+    #[test]
+    fn test_minimum_quantity() {
+        let mut order_book = OrderBook::new();
+
+        order_book.add(Side::Sell, 10, 3).unwrap();
+        order_book.add(Side::Sell, 11, 2).unwrap();
+
+        let bid = NewOrder { min_quantity: Some(6), ..NewOrder::limit(Side::Buy, 12, 8) };
+        assert_eq!(Err(Error::Rejected { reason: Reject::NotEnoughLiquidity }), order_book.submit(bid));
+        assert_eq!(3, order_book.level(Side::Sell, 10).unwrap().quantity());
+
+        // Only 3 crosses at 10, enough for the minimum, and the rest rests
+        let bid = NewOrder { min_quantity: Some(3), ..NewOrder::limit(Side::Buy, 10, 8) };
+        assert_eq!(Added { id: 2, filled: 3, cost: 3 * 10, resting: 5, cancelled: 0 }, order_book.submit(bid).unwrap());
+    }
+
+    #[test]
+    fn test_all_or_none_orders_keep_their_place() {
+        let mut order_book = OrderBook::new();
+        let all_or_none = |price, amount| NewOrder { all_or_none: true, ..NewOrder::limit(Side::Sell, price, amount) };
+
+        order_book.add(Side::Sell, 10, 2).unwrap();
+        let big = order_book.submit(all_or_none(10, 5)).unwrap().id;
+        order_book.add(Side::Sell, 10, 3).unwrap();
+        order_book.submit(all_or_none(11, 4)).unwrap();
+        order_book.add(Side::Sell, 11, 2).unwrap();
+
+        // 4 cannot fill the all-or-none order, the orders either side of it trade
+        let mut fills = Vec::new();
+        order_book.take_into(Side::Buy, 4, TakeMode::Partial, &mut fills).unwrap();
+        assert_eq!(vec![(0, 2), (2, 2)], fills.iter().map(|fill| (fill.maker_id, fill.amount)).collect::<Vec<_>>());
+        assert_eq!(big, order_book.level(Side::Sell, 10).unwrap().orders().next().unwrap().id);
+
+        // 7 clears the level, then passes over the all-or-none order at 11
+        fills.clear();
+        order_book.take_into(Side::Buy, 7, TakeMode::Partial, &mut fills).unwrap();
+        assert_eq!(
+            vec![(1, 5), (2, 1), (4, 1)],
+            fills.iter().map(|fill| (fill.maker_id, fill.amount)).collect::<Vec<_>>()
+        );
+
+        let quote = order_book.quote(Side::Buy, 3);
+        assert_eq!((1, 11), (quote.filled, quote.cost));
+        assert_eq!(
+            Taken { filled: 1, cost: 11, unfilled: 2 },
+            order_book.take(Side::Buy, 3, TakeMode::Partial).unwrap()
+        );
+
+        assert_eq!(
+            Taken { filled: 4, cost: 44, unfilled: 0 },
+            order_book.take(Side::Buy, 4, TakeMode::FillOrKill).unwrap()
+        );
+        assert!(order_book.is_empty());
+    }
+
+    #[test]
+    fn test_all_or_none_taker() {
+        let mut order_book = OrderBook::new();
+
+        order_book.add(Side::Sell, 10, 3).unwrap();
+        order_book.add(Side::Sell, 11, 2).unwrap();
+
+        // 6 cannot fill in full, so the bid rests whole across the asks
+        let bid = NewOrder { all_or_none: true, ..NewOrder::limit(Side::Buy, 11, 6) };
+        assert_eq!(Added { id: 2, filled: 0, cost: 0, resting: 6, cancelled: 0 }, order_book.submit(bid).unwrap());
+        assert_eq!(Some(11), order_book.best_price(Side::Buy));
+        assert_eq!(Some(10), order_book.best_price(Side::Sell));
+
+        // Immediate-or-cancel as well, it is fill-or-kill in all but name
+        let ioc = NewOrder { time_in_force: TimeInForce::ImmediateOrCancel, ..bid };
+        assert_eq!(Err(Error::Rejected { reason: Reject::NotEnoughLiquidity }), order_book.submit(ioc));
+
+        let ioc = NewOrder { amount: 5, ..ioc };
+        let added = order_book.submit(ioc).unwrap();
+        assert_eq!(Added { id: 3, filled: 5, cost: 3 * 10 + 2 * 11, resting: 0, cancelled: 0 }, added);
+
+        // The resting bid only ever trades in full
+        assert_eq!(
+            Taken { filled: 0, cost: 0, unfilled: 4 },
+            order_book.take(Side::Sell, 4, TakeMode::Partial).unwrap()
+        );
+        assert_eq!(
+            Taken { filled: 6, cost: 66, unfilled: 0 },
+            order_book.take(Side::Sell, 6, TakeMode::Partial).unwrap()
+        );
+
+        let iceberg = NewOrder { peak: Some(2), ..bid };
+        assert_eq!(Err(Error::Rejected { reason: Reject::AllOrNoneIceberg }), order_book.submit(iceberg));
+    }
+
+    #[test]
+    fn test_all_or_none_and_minimum_quantity_against_own_orders() {
+        // Asks at 10 from owner 2, then owner 1
+        let book = |mode| {
+            let mut order_book = OrderBook::new();
+            order_book.set_self_trade_prevention(mode);
+            for owner in [2, 1] {
+                order_book.submit(NewOrder { owner: Some(owner), ..NewOrder::limit(Side::Sell, 10, 2) }).unwrap();
+            }
+            order_book
+        };
+        let bid = NewOrder { owner: Some(1), ..NewOrder::limit(Side::Buy, 10, 4) };
+
+        // Only 2 could ever trade, so the all-or-none bid trades nothing
+        for mode in [SelfTradePrevention::CancelResting, SelfTradePrevention::DecrementBoth] {
+            let mut order_book = book(mode);
+            let added = order_book.submit(NewOrder { all_or_none: true, ..bid }).unwrap();
+            assert_eq!(Added { id: 2, filled: 0, cost: 0, resting: 4, cancelled: 0 }, added, "{:?}", mode);
+            assert_eq!(4, order_book.total_quantity(Side::Sell));
+        }
+
+        let mut order_book = book(SelfTradePrevention::CancelResting);
+        assert_eq!(
+            Err(Error::Rejected { reason: Reject::NotEnoughLiquidity }),
+            order_book.submit(NewOrder { min_quantity: Some(3), ..bid })
+        );
+        let added = order_book.submit(NewOrder { min_quantity: Some(2), ..bid }).unwrap();
+        assert_eq!(Added { id: 2, filled: 2, cost: 20, resting: 2, cancelled: 0 }, added);
+        assert_eq!(None, order_book.best_price(Side::Sell));
+
+        // The sweep trades the other owner's ask ahead of the own one before
+        // self-trade prevention ends it, so both fill from there
+        let mut order_book = book(SelfTradePrevention::CancelIncoming);
+        let added = order_book.submit(NewOrder { all_or_none: true, amount: 2, ..bid }).unwrap();
+        assert_eq!(Added { id: 2, filled: 2, cost: 20, resting: 0, cancelled: 0 }, added);
+
+        let mut order_book = book(SelfTradePrevention::CancelIncoming);
+        let added = order_book.submit(NewOrder { min_quantity: Some(2), ..bid }).unwrap();
+        assert_eq!(Added { id: 2, filled: 2, cost: 20, resting: 0, cancelled: 2 }, added);
+        assert_eq!(Some(10), order_book.best_price(Side::Sell));
+    }
+
+    #[test]
+    fn test_orders_expire() {
+        let clock = ManualClock::new(100);
+        let mut order_book = OrderBook::with_clock(Fifo, clock.clone());
+        let good_till = |price, time_in_force| NewOrder { time_in_force, ..NewOrder::limit(Side::Sell, price, 2) };
+
+        let timed = order_book.submit(good_till(10, TimeInForce::GoodTillTime(150))).unwrap().id;
+        let sequenced = order_book.submit(good_till(11, TimeInForce::GoodTillSequence(3))).unwrap().id;
+        order_book.add(Side::Sell, 12, 1).unwrap();
+
+        // Three ids are out, so the good-till-sequence order is due
+        let mut events = Events::default();
+        let cancelled = |events: &mut Events| {
+            let cancelled = events.0.iter().filter_map(|event| match *event {
+                BookEvent::OrderCancelled { id, amount, .. } => Some((id, amount)),
+                _ => None,
+            });
+            let cancelled = cancelled.collect::<Vec<_>>();
+            events.0.clear();
+            cancelled
+        };
+        order_book.expire_into(&mut events);
+        assert_eq!(vec![(sequenced, 2)], cancelled(&mut events));
+        assert_eq!(Err(Error::UnknownOrder { id: sequenced }), order_book.cancel(sequenced));
+
+        // The take finds the timed order already gone
+        clock.set(150);
+        assert_eq!(
+            Taken { filled: 1, cost: 12, unfilled: 1 },
+            order_book.take_into(Side::Buy, 2, TakeMode::Partial, &mut events).unwrap()
+        );
+        assert_eq!(vec![(timed, 2)], cancelled(&mut events));
+        assert!(order_book.is_empty());
+
+        let expired = Err(Error::Rejected { reason: Reject::Expired });
+        assert_eq!(expired, order_book.submit(good_till(10, TimeInForce::GoodTillTime(150))));
+        assert_eq!(expired, order_book.submit(good_till(10, TimeInForce::GoodTillSequence(3))));
+
+        // An order that filled before its time has nothing left to expire
+        order_book.submit(good_till(10, TimeInForce::GoodTillTime(200))).unwrap();
+        order_book.take(Side::Buy, 2, TakeMode::Partial).unwrap();
+        clock.advance(100);
+        order_book.expire_into(&mut events);
+        assert!(cancelled(&mut events).is_empty());
+
+        // Nor does its id linger long in the index
+        for _ in 0..100 {
+            order_book.submit(good_till(10, TimeInForce::GoodTillTime(1_000))).unwrap();
+            order_book.take(Side::Buy, 2, TakeMode::Partial).unwrap();
+        }
+        assert!(order_book.expiring_len <= 2);
+        assert_eq!(order_book.expiring_len, order_book.time_to_expiring.values().map(Vec::len).sum::<usize>());
+    }
+
+    #[test]
+    fn test_read_only_views() {
+        let mut order_book = OrderBook::new();
+
+        order_book.add(Side::Buy, 9, 4).unwrap();
+        order_book.add(Side::Buy, 8, 1).unwrap();
+        order_book.add(Side::Buy, 9, 2).unwrap();
+        order_book.add(Side::Buy, 7, 3).unwrap();
+        order_book.submit(NewOrder { peak: Some(1), ..NewOrder::limit(Side::Sell, 11, 5) }).unwrap();
+
+        let prices = order_book.levels(Side::Buy).map(|(price, _)| price).collect::<Vec<_>>();
+        assert_eq!(vec![9, 8, 7], prices);
+        let prices = order_book.levels_in_range(Side::Buy, 7..9).map(|(price, _)| price).collect::<Vec<_>>();
+        assert_eq!(vec![8, 7], prices);
+        assert_eq!(Some(11), order_book.levels_in_range(Side::Sell, 10..).next().map(|(price, _)| price));
+
+        assert_eq!(vec![0, 2], order_book.orders_at(Side::Buy, 9).map(|order| order.id).collect::<Vec<_>>());
+        assert_eq!(0, order_book.orders_at(Side::Sell, 9).count());
+
+        assert_eq!(6, order_book.depth_at(Side::Buy, 9));
+        assert_eq!(0, order_book.depth_at(Side::Buy, 10));
+        assert_eq!(10, order_book.total_quantity(Side::Buy));
+        assert_eq!(1, order_book.total_quantity(Side::Sell));
+    }
+
+    // Keeps every event a listener hears
+    #[derive(Default)]
+    struct Events(Vec<BookEvent>);
+
+    impl BookListener for Events {
+        fn on_event(&mut self, event: BookEvent) {
+            self.0.push(event);
+        }
+    }
+
+    #[test]
+    fn test_listener_hears_every_change() {
+        let mut order_book = OrderBook::new();
+        let mut events = Events::default();
+        let sell = Side::Sell;
+
+        order_book.add_into(sell, 10, 3, &mut events).unwrap();
+        order_book.add_into(sell, 10, 2, &mut events).unwrap();
+        assert_eq!(
+            vec![
+                BookEvent::LevelCreated { side: sell, price: 10 },
+                BookEvent::OrderAdded { id: 0, side: sell, price: 10, amount: 3 },
+                BookEvent::TopOfBookChanged { side: sell, price: Some(10), quantity: 3 },
+                BookEvent::OrderAdded { id: 1, side: sell, price: 10, amount: 2 },
+                BookEvent::TopOfBookChanged { side: sell, price: Some(10), quantity: 5 },
+            ],
+            std::mem::take(&mut events.0)
+        );
+
+        order_book.take_into(Side::Buy, 4, TakeMode::Partial, &mut events).unwrap();
+        let filled = |maker_id, amount, maker_remaining| BookEvent::OrderFilled {
+            side: sell,
+            fill: Fill { maker_id, price: 10, amount, maker_remaining },
+        };
+        assert_eq!(
+            vec![
+                filled(0, 3, 0),
+                filled(1, 1, 1),
+                BookEvent::TopOfBookChanged { side: sell, price: Some(10), quantity: 1 },
+            ],
+            std::mem::take(&mut events.0)
+        );
+
+        order_book.cancel_into(1, &mut events).unwrap();
+        assert_eq!(
+            vec![
+                BookEvent::OrderCancelled { id: 1, side: sell, price: 10, amount: 1 },
+                BookEvent::LevelRemoved { side: sell, price: 10 },
+                BookEvent::TopOfBookChanged { side: sell, price: None, quantity: 0 },
+            ],
+            std::mem::take(&mut events.0)
+        );
+
+        // Cutting an order leaves it in place, moving it takes it off and
+        // adds it again
+        order_book.add(sell, 11, 5).unwrap();
+        order_book.amend_into(2, 11, 3, &mut events).unwrap();
+        order_book.amend_into(2, 12, 3, &mut events).unwrap();
+        assert_eq!(
+            vec![
+                BookEvent::OrderReduced { id: 2, side: sell, price: 11, amount: 3 },
+                BookEvent::TopOfBookChanged { side: sell, price: Some(11), quantity: 3 },
+                BookEvent::OrderCancelled { id: 2, side: sell, price: 11, amount: 3 },
+                BookEvent::LevelRemoved { side: sell, price: 11 },
+                BookEvent::LevelCreated { side: sell, price: 12 },
+                BookEvent::OrderAdded { id: 2, side: sell, price: 12, amount: 3 },
+                BookEvent::TopOfBookChanged { side: sell, price: Some(12), quantity: 3 },
+            ],
+            events.0
+        );
+    }
+
+    #[test]
+    fn test_top_of_book_is_reported_per_call() {
+        let clock = ManualClock::new(0);
+        let mut order_book = OrderBook::with_clock(Fifo, clock.clone());
+        let mut events = Events::default();
+        let sell = Side::Sell;
+        let add = |price, amount, time_in_force| Command::Add { side: sell, price, amount, time_in_force };
+
+        // A call heard by no one leaves nothing behind for the next listener
+        order_book.add_into(sell, 10, 1, &mut events).unwrap();
+        order_book.add(sell, 9, 1).unwrap();
+        events.0.clear();
+        order_book.apply_into(add(8, 2, TimeInForce::GoodTillTime(5)), &mut events).unwrap();
+        assert_eq!(
+            vec![
+                BookEvent::LevelCreated { side: sell, price: 8 },
+                BookEvent::OrderAdded { id: 2, side: sell, price: 8, amount: 2 },
+                BookEvent::TopOfBookChanged { side: sell, price: Some(8), quantity: 2 },
+            ],
+            std::mem::take(&mut events.0)
+        );
+
+        // The expiry and the new order both move the top, the call reports
+        // where it ends up once
+        clock.set(5);
+        order_book.apply_into(add(9, 3, TimeInForce::GoodTillCancel), &mut events).unwrap();
+        assert_eq!(
+            vec![
+                BookEvent::OrderCancelled { id: 2, side: sell, price: 8, amount: 2 },
+                BookEvent::LevelRemoved { side: sell, price: 8 },
+                BookEvent::OrderAdded { id: 3, side: sell, price: 9, amount: 3 },
+                BookEvent::TopOfBookChanged { side: sell, price: Some(9), quantity: 4 },
+            ],
+            events.0
+        );
+    }
+}
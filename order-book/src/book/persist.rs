@@ -0,0 +1,415 @@
+use std::collections::{BTreeMap, HashMap};
+use std::io::{Read, Write};
+
+use super::{
+    BookSide, Location, Order, OrderBook, OrderId, Peg, PegGroup, PegReference, SelfTradePrevention, Side, Stop,
+};
+use crate::clock::Clock;
+use crate::error::{Error, Result};
+use crate::journal::crc32;
+use crate::policy::MatchingPolicy;
+
+const MAGIC: [u8; 4] = *b"OBSN";
+// Bumped whenever the layout below changes, older snapshots are refused
+const VERSION: u32 = 2;
+// Magic, version and body length in front of the body
+const HEADER_LEN: usize = 4 + 4 + 8;
+
+impl<P: MatchingPolicy, C: Clock> OrderBook<P, C> {
+    /// Writes every resting order, in queue order at each level, every stop
+    /// and every pending expiry to `writer`, along with `sequence`.
+    ///
+    /// `sequence` is the caller's to pick, usually the journal's next
+    /// sequence number so a restart knows which records the snapshot already
+    /// holds. The snapshot is a magic number, a format version, the length of
+    /// the body, the body and a CRC-32 of the body, all little-endian. The
+    /// self-trade prevention mode is saved, since the journal records changes
+    /// to it; the matching policy and clock belong to the book that loads it
+    /// and are not.
+    pub fn save_snapshot(&self, sequence: u64, writer: &mut impl Write) -> Result<()> {
+        let mut body = Vec::new();
+        put_u64(&mut body, sequence);
+        put_u64(&mut body, self.next_order_id);
+        put_option(&mut body, self.last_price);
+        body.push(encode_self_trade_prevention(self.self_trade_prevention));
+
+        for book_side in [&self.bids, &self.asks] {
+            save_side(&mut body, book_side);
+        }
+
+        // Ids of orders that have left the book since are not worth keeping
+        for expiring in [&self.time_to_expiring, &self.sequence_to_expiring] {
+            let live = expiring
+                .iter()
+                .flat_map(|(&expiry, ids)| ids.iter().map(move |&id| (expiry, id)))
+                .filter(|(_, id)| self.order_id_to_location.contains_key(id))
+                .collect::<Vec<_>>();
+
+            put_u64(&mut body, live.len() as u64);
+            for (expiry, id) in live {
+                put_u64(&mut body, expiry);
+                put_u64(&mut body, id);
+            }
+        }
+
+        let mut snapshot = Vec::with_capacity(HEADER_LEN + body.len() + 4);
+        snapshot.extend_from_slice(&MAGIC);
+        snapshot.extend_from_slice(&VERSION.to_le_bytes());
+        put_u64(&mut snapshot, body.len() as u64);
+        snapshot.extend_from_slice(&body);
+        snapshot.extend_from_slice(&crc32(&body).to_le_bytes());
+
+        writer.write_all(&snapshot)?;
+
+        Ok(())
+    }
+
+    /// Replaces everything on the book with the snapshot in `reader`,
+    /// returning the sequence number it was saved with.
+    ///
+    /// A snapshot that is cut short, damaged or of another format version
+    /// fails with `Error::BadSnapshot` and leaves the book as it was.
+    pub fn load_snapshot(&mut self, reader: &mut impl Read) -> Result<u64> {
+        let mut buf = Vec::new();
+        reader.read_to_end(&mut buf)?;
+
+        let mut decoder = Decoder { buf: &buf };
+        if decoder.take::<4>()? != MAGIC {
+            return Err(bad_snapshot("not a book snapshot"));
+        }
+        if u32::from_le_bytes(decoder.take()?) != VERSION {
+            return Err(bad_snapshot("unknown format version"));
+        }
+
+        let body_len = decoder.take_u64()?;
+        if decoder.buf.len() as u64 != body_len.saturating_add(4) {
+            return Err(bad_snapshot("length does not match"));
+        }
+        let (body, crc) = decoder.buf.split_at(body_len as usize);
+        if crc32(body).to_le_bytes() != crc {
+            return Err(bad_snapshot("checksum does not match"));
+        }
+
+        // Everything is rebuilt off to the side so a bad body leaves the book alone
+        let mut decoder = Decoder { buf: body };
+        let sequence = decoder.take_u64()?;
+        let next_order_id = decoder.take_u64()?;
+        let last_price = decoder.take_option()?;
+        let self_trade_prevention = decode_self_trade_prevention(decoder.take_u8()?)?;
+
+        let mut order_id_to_location = HashMap::new();
+        let mut stop_id_to_trigger = HashMap::new();
+        let mut bids = BookSide::new(Side::Buy);
+        let mut asks = BookSide::new(Side::Sell);
+        for book_side in [&mut bids, &mut asks] {
+            load_side(&mut decoder, book_side, &mut order_id_to_location, &mut stop_id_to_trigger)?;
+        }
+
+        let mut expiring = [BTreeMap::new(), BTreeMap::new()];
+        for expiring in &mut expiring {
+            for _ in 0..decoder.take_u64()? {
+                let expiry = decoder.take_u64()?;
+                let id = decoder.take_u64()?;
+                expiring.entry(expiry).or_insert_with(Vec::new).push(id);
+            }
+        }
+
+        if !decoder.buf.is_empty() {
+            return Err(bad_snapshot("trailing bytes"));
+        }
+
+        let expiring_len = expiring.iter().flat_map(BTreeMap::values).map(Vec::len).sum();
+        let [time_to_expiring, sequence_to_expiring] = expiring;
+        self.bids = bids;
+        self.asks = asks;
+        self.order_id_to_location = order_id_to_location;
+        self.stop_id_to_trigger = stop_id_to_trigger;
+        self.time_to_expiring = time_to_expiring;
+        self.sequence_to_expiring = sequence_to_expiring;
+        self.expiring_len = expiring_len;
+        self.next_order_id = next_order_id;
+        self.last_price = last_price;
+        self.self_trade_prevention = self_trade_prevention;
+
+        Ok(sequence)
+    }
+}
+
+// Levels best price last, since only queue order within a level matters, then
+// stops by trigger
+fn save_side(body: &mut Vec<u8>, book_side: &BookSide) {
+    put_u64(body, book_side.price_to_level.len() as u64);
+    for (&price, level) in &book_side.price_to_level {
+        put_u64(body, price);
+        put_u64(body, level.orders.len() as u64);
+
+        for order in &level.orders {
+            put_u64(body, order.id);
+            put_u64(body, order.amount);
+            put_u64(body, order.hidden);
+            put_option(body, order.peak);
+            match order.peg {
+                Some(peg) => {
+                    body.push(1 + encode_reference(peg.reference));
+                    put_u64(body, peg.offset as u64);
+                },
+                None => body.push(0),
+            }
+            put_option(body, order.owner);
+            body.push(order.all_or_none as u8);
+        }
+    }
+
+    let stop_count = book_side.trigger_to_stops.values().map(Vec::len).sum::<usize>();
+    put_u64(body, stop_count as u64);
+    for (id, stop) in book_side.trigger_to_stops.values().flatten() {
+        put_u64(body, *id);
+        put_u64(body, stop.trigger);
+        put_u64(body, stop.amount);
+        put_option(body, stop.limit);
+        put_option(body, stop.owner);
+    }
+}
+
+// Orders join their levels with fresh seqs in the order they were saved, so
+// they keep their places in the queue
+fn load_side(
+    decoder: &mut Decoder<'_>,
+    book_side: &mut BookSide,
+    order_id_to_location: &mut HashMap<OrderId, Location>,
+    stop_id_to_trigger: &mut HashMap<OrderId, (Side, u64)>
+) -> Result<()> {
+    let side = book_side.side;
+
+    for _ in 0..decoder.take_u64()? {
+        let price = decoder.take_u64()?;
+        let order_count = decoder.take_u64()?;
+        if order_count == 0 {
+            return Err(bad_snapshot("empty level"));
+        }
+
+        let level = book_side.price_to_level.entry(price).or_default();
+        for _ in 0..order_count {
+            let id = decoder.take_u64()?;
+            let amount = decoder.take_u64()?;
+            let hidden = decoder.take_u64()?;
+            let peak = decoder.take_option()?;
+            let peg = match decoder.take_u8()? {
+                0 => None,
+                tag => Some(Peg {
+                    reference: decode_reference(tag - 1)?,
+                    offset: decoder.take_u64()? as i64,
+                }),
+            };
+            let owner = decoder.take_option()?;
+            let all_or_none = decoder.take_u8()? != 0;
+
+            if amount == 0 {
+                return Err(bad_snapshot("order with nothing shown"));
+            }
+
+            let seq = book_side.next_seq;
+            book_side.next_seq += 1;
+            level.push_back(Order { id, amount, seq, hidden, peak, peg, owner, all_or_none });
+
+            if let Some(peg) = peg {
+                book_side.peg_groups.entry(peg).or_insert(PegGroup { price, ids: Vec::new() }).ids.push(id);
+            }
+            if order_id_to_location.insert(id, Location { side, price, seq }).is_some() {
+                return Err(bad_snapshot("order id used twice"));
+            }
+        }
+
+        book_side.order_counts.increment(price, order_count);
+    }
+
+    for _ in 0..decoder.take_u64()? {
+        let id = decoder.take_u64()?;
+        let stop = Stop {
+            side,
+            trigger: decoder.take_u64()?,
+            amount: decoder.take_u64()?,
+            limit: decoder.take_option()?,
+            owner: decoder.take_option()?,
+        };
+
+        book_side.trigger_to_stops.entry(stop.trigger).or_default().push((id, stop));
+        stop_id_to_trigger.insert(id, (side, stop.trigger));
+    }
+
+    Ok(())
+}
+
+fn bad_snapshot(reason: &'static str) -> Error {
+    Error::BadSnapshot { reason }
+}
+
+fn put_u64(buf: &mut Vec<u8>, value: u64) {
+    buf.extend_from_slice(&value.to_le_bytes());
+}
+
+// A 0 or 1 tag, then the value when there is one
+fn put_option(buf: &mut Vec<u8>, value: Option<u64>) {
+    match value {
+        Some(value) => {
+            buf.push(1);
+            put_u64(buf, value);
+        },
+        None => buf.push(0),
+    }
+}
+
+fn encode_reference(reference: PegReference) -> u8 {
+    match reference {
+        PegReference::BestBid => 0,
+        PegReference::BestAsk => 1,
+        PegReference::Midpoint => 2,
+    }
+}
+
+fn decode_reference(tag: u8) -> Result<PegReference> {
+    match tag {
+        0 => Ok(PegReference::BestBid),
+        1 => Ok(PegReference::BestAsk),
+        2 => Ok(PegReference::Midpoint),
+        _ => Err(bad_snapshot("unknown peg reference")),
+    }
+}
+
+fn encode_self_trade_prevention(self_trade_prevention: SelfTradePrevention) -> u8 {
+    match self_trade_prevention {
+        SelfTradePrevention::CancelResting => 0,
+        SelfTradePrevention::CancelIncoming => 1,
+        SelfTradePrevention::CancelBoth => 2,
+        SelfTradePrevention::DecrementBoth => 3,
+    }
+}
+
+fn decode_self_trade_prevention(tag: u8) -> Result<SelfTradePrevention> {
+    match tag {
+        0 => Ok(SelfTradePrevention::CancelResting),
+        1 => Ok(SelfTradePrevention::CancelIncoming),
+        2 => Ok(SelfTradePrevention::CancelBoth),
+        3 => Ok(SelfTradePrevention::DecrementBoth),
+        _ => Err(bad_snapshot("unknown self-trade prevention")),
+    }
+}
+
+// Reads fields off the front of a snapshot, failing once it runs out
+struct Decoder<'a> {
+    buf: &'a [u8],
+}
+
+impl Decoder<'_> {
+    fn take<const N: usize>(&mut self) -> Result<[u8; N]> {
+        if self.buf.len() < N {
+            return Err(bad_snapshot("cut short"));
+        }
+        let (bytes, rest) = self.buf.split_at(N);
+        self.buf = rest;
+
+        Ok(bytes.try_into().unwrap())
+    }
+
+    fn take_u8(&mut self) -> Result<u8> {
+        self.take::<1>().map(|[byte]| byte)
+    }
+
+    fn take_u64(&mut self) -> Result<u64> {
+        self.take().map(u64::from_le_bytes)
+    }
+
+    fn take_option(&mut self) -> Result<Option<u64>> {
+        match self.take_u8()? {
+            0 => Ok(None),
+            1 => self.take_u64().map(Some),
+            _ => Err(bad_snapshot("bad option tag")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::book::{NewOrder, TakeMode, TimeInForce};
+    use crate::clock::ManualClock;
+    use crate::policy::Fifo;
+
+    fn book(clock: &ManualClock) -> OrderBook<Fifo, ManualClock> {
+        let mut order_book = OrderBook::with_clock(Fifo, clock.clone());
+
+        order_book.add(Side::Buy, 9, 4).unwrap();
+        order_book.submit(NewOrder { owner: Some(7), ..NewOrder::limit(Side::Buy, 9, 2) }).unwrap();
+        order_book.submit(NewOrder { all_or_none: true, ..NewOrder::limit(Side::Buy, 8, 5) }).unwrap();
+        order_book.submit(NewOrder { peak: Some(1), ..NewOrder::limit(Side::Sell, 11, 5) }).unwrap();
+        let good_till_time = TimeInForce::GoodTillTime(50);
+        order_book.submit(NewOrder { time_in_force: good_till_time, ..NewOrder::limit(Side::Sell, 12, 3) }).unwrap();
+        let good_till_sequence = TimeInForce::GoodTillSequence(20);
+        order_book.submit(NewOrder { time_in_force: good_till_sequence, ..NewOrder::limit(Side::Sell, 13, 1) }).unwrap();
+        let peg = Peg { reference: PegReference::BestBid, offset: -1 };
+        order_book.submit(NewOrder::pegged(Side::Buy, peg, 3)).unwrap();
+        order_book.submit_stop(Stop { side: Side::Sell, trigger: 8, amount: 2, limit: None, owner: None }).unwrap();
+        order_book.add(Side::Sell, 9, 1).unwrap();
+
+        order_book
+    }
+
+    fn saved(order_book: &OrderBook<Fifo, ManualClock>, sequence: u64) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        order_book.save_snapshot(sequence, &mut bytes).unwrap();
+        bytes
+    }
+
+    #[test]
+    fn test_snapshot_round_trip() {
+        let clock = ManualClock::new(0);
+        let mut order_book = book(&clock);
+        order_book.set_self_trade_prevention(SelfTradePrevention::DecrementBoth);
+        let bytes = saved(&order_book, 42);
+
+        let mut loaded = OrderBook::with_clock(Fifo, clock.clone());
+        loaded.add(Side::Sell, 100, 1).unwrap();
+        assert_eq!(Ok(42), loaded.load_snapshot(&mut &bytes[..]));
+        assert_eq!(order_book.l3_snapshot(), loaded.l3_snapshot());
+        assert_eq!((order_book.sequence(), order_book.last_price()), (loaded.sequence(), loaded.last_price()));
+        assert_eq!(1, loaded.stop_count());
+        assert_eq!(SelfTradePrevention::DecrementBoth, loaded.self_trade_prevention);
+        assert_eq!(bytes, saved(&loaded, 42));
+
+        // Both carry on the same: expiries, pegs, stops and ids all line up
+        clock.set(60);
+        for book in [&mut order_book, &mut loaded] {
+            book.take(Side::Buy, 2, TakeMode::Partial).unwrap();
+            book.add(Side::Sell, 8, 7).unwrap();
+        }
+        assert_eq!(order_book.l3_snapshot(), loaded.l3_snapshot());
+        assert_eq!(0, loaded.depth_at(Side::Sell, 12));
+        assert_eq!((order_book.stop_count(), order_book.last_price()), (loaded.stop_count(), loaded.last_price()));
+    }
+
+    #[test]
+    fn test_bad_snapshot_leaves_the_book_alone() {
+        let clock = ManualClock::new(0);
+        let bytes = saved(&book(&clock), 0);
+        let mut order_book = OrderBook::with_clock(Fifo, clock);
+        order_book.add(Side::Sell, 100, 1).unwrap();
+        let before = order_book.l3_snapshot();
+
+        let mut damaged = bytes.clone();
+        damaged[HEADER_LEN + 3] ^= 1;
+        let mut newer = bytes.clone();
+        newer[4] += 1;
+
+        for (bytes, reason) in [
+            (damaged, "checksum does not match"),
+            (newer, "unknown format version"),
+            (bytes[..bytes.len() - 1].to_vec(), "length does not match"),
+            (b"OBS".to_vec(), "cut short"),
+            (b"JUNK".to_vec(), "not a book snapshot"),
+        ] {
+            assert_eq!(Err(Error::BadSnapshot { reason }), order_book.load_snapshot(&mut &bytes[..]));
+        }
+        assert_eq!(before, order_book.l3_snapshot());
+    }
+}
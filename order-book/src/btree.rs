@@ -1,4 +1,6 @@
 // An implementation of Algorithmica's search tree in Rust!
+// Not wired into the book yet, only exercised by its tests.
+#![allow(dead_code, clippy::needless_range_loop)]
 
 use core::arch::x86_64::*;
 
@@ -215,7 +217,7 @@ mod tests {
     fn test_cmp() {
         // Set first 8 elements to 1 to 8 (inclusive)
         let mut array = TestArray([0; 64]);
-        array.0[0..8].copy_from_slice(&vec![1,2,3,4,5,6,7,8]);
+        array.0[0..8].copy_from_slice(&[1,2,3,4,5,6,7,8]);
 
         let mut rng = rand::thread_rng();
 
@@ -273,7 +275,7 @@ mod tests {
         let mut array = TestArray([0; 64]);
         array.0[0..32].copy_from_slice(&(1..33).collect::<Vec<i32>>());
         // Set next 32 elements to i32::MAX
-        array.0[32..64].copy_from_slice(&vec![i32::MAX; 32]);
+        array.0[32..64].copy_from_slice(&[i32::MAX; 32]);
 
         let array_ptr = array.0.as_mut_ptr();
 
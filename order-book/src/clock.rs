@@ -0,0 +1,72 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Where the book reads the time that good-till-time orders expire by.
+///
+/// Times are plain numbers, the book only compares them with the times
+/// orders are given.
+pub trait Clock {
+    fn now(&self) -> u64;
+}
+
+/// Nanoseconds since the Unix epoch, by the system's wall clock.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since_epoch| since_epoch.as_nanos() as u64)
+    }
+}
+
+/// A clock that only moves when told to, so tests and replays expire orders
+/// at the same points every run.
+///
+/// Clones share one time, so a clone kept outside the book moves the book's.
+#[derive(Debug, Clone, Default)]
+pub struct ManualClock {
+    now: Arc<AtomicU64>,
+}
+
+impl ManualClock {
+    pub fn new(now: u64) -> Self {
+        Self {
+            now: Arc::new(AtomicU64::new(now)),
+        }
+    }
+
+    pub fn set(&self, now: u64) {
+        self.now.store(now, Ordering::Relaxed);
+    }
+
+    pub fn advance(&self, by: u64) {
+        self.now.fetch_add(by, Ordering::Relaxed);
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> u64 {
+        self.now.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_manual_clock_is_shared() {
+        let clock = ManualClock::new(5);
+        let kept = clock.clone();
+
+        kept.advance(3);
+        assert_eq!(8, clock.now());
+
+        kept.set(2);
+        assert_eq!(2, clock.now());
+    }
+}
@@ -0,0 +1,263 @@
+use crate::book::{NewOrder, OrderId, SelfTradePrevention, Side, Stop, TakeMode, TimeInForce};
+use crate::error::{Error, Result};
+
+pub(crate) const PLUS: u8 = 0x2b;
+pub(crate) const MINUS: u8 = 0x2d;
+pub(crate) const EQUALS: u8 = 0x3d;
+pub(crate) const DOLLAR: u8 = 0x24;
+pub(crate) const TILDE: u8 = 0x7e;
+pub(crate) const BID: u8 = 0x62;   // b
+pub(crate) const SPACE: u8 = 0x20;
+pub(crate) const NEWLINE: u8 = 0x0a;
+
+/// One line of the text protocol, or one call into the book.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Command {
+    /// `+ price amount` sells, `b price amount` buys at a limit, either may
+    /// end in ` gtc`, ` ioc`, ` fok` or ` post`
+    Add { side: Side, price: u64, amount: u64, time_in_force: TimeInForce },
+    /// `- index` removes the ask at that position
+    RemoveAt { side: Side, index: u64 },
+    /// `= amount` buys from the asks, `= amount limit` stops short of
+    /// asks above `limit`
+    Take { side: Side, amount: u64, limit: Option<u64>, mode: TakeMode },
+    /// `$ budget` buys from the asks for at most `budget`
+    TakeNotional { side: Side, budget: u64 },
+    /// `~ id price amount` changes a resting order
+    Amend { id: OrderId, price: u64, amount: u64 },
+    Cancel { id: OrderId },
+    /// A limit order with everything `NewOrder` can carry, no text form
+    Submit(NewOrder),
+    /// A stop or stop-limit order, no text form
+    SubmitStop(Stop),
+    /// Changes the book's self-trade prevention, no text form
+    SetSelfTradePrevention(SelfTradePrevention),
+}
+
+/// Turns a buffer of text protocol lines into `Command`s.
+///
+/// A bad line comes out as an `Error::Parse` and parsing picks up again on
+/// the next line, so one typo does not lose the rest of the input.
+pub struct Parser<'a> {
+    buf: &'a [u8],
+    i: usize,
+}
+
+impl<'a> Parser<'a> {
+    pub fn new(buf: &'a [u8]) -> Self {
+        Self { buf, i: 0 }
+    }
+
+    // matching every character, state
+    // takes too long to long to parse the integers
+    // we can parse line by line very quickly
+    // so for each integer, iterate and build it up BUT
+    // we also check every stage that the char is in our desired range 0x30 to 0x39
+    // we break if it isn't
+    fn parse_number(&mut self) -> Result<u64> {
+        let start = self.i;
+        let mut num: u64 = 0;
+
+        while let Some(&c) = self.buf.get(self.i) {
+            if !c.is_ascii_digit() {
+                break;
+            }
+            num = num
+                .checked_mul(10)
+                .and_then(|num| num.checked_add((c - 0x30) as u64))
+                .ok_or(Error::Parse { offset: start, expected: "number below 2^64" })?;
+            self.i += 1;
+        }
+
+        if self.i == start {
+            return Err(Error::Parse { offset: self.i, expected: "digit" });
+        }
+
+        Ok(num)
+    }
+
+    fn parse_space(&mut self) -> Result<()> {
+        if self.buf.get(self.i) != Some(&SPACE) {
+            return Err(Error::Parse { offset: self.i, expected: "space" });
+        }
+        self.i += 1;
+
+        Ok(())
+    }
+
+    // Good till cancel unless the line names another
+    fn parse_time_in_force(&mut self) -> Result<TimeInForce> {
+        if self.buf.get(self.i) != Some(&SPACE) {
+            return Ok(TimeInForce::GoodTillCancel);
+        }
+        self.i += 1;
+
+        let start = self.i;
+        while self.buf.get(self.i).is_some_and(|&c| c != NEWLINE) {
+            self.i += 1;
+        }
+
+        match &self.buf[start..self.i] {
+            b"gtc" => Ok(TimeInForce::GoodTillCancel),
+            b"ioc" => Ok(TimeInForce::ImmediateOrCancel),
+            b"fok" => Ok(TimeInForce::FillOrKill),
+            b"post" => Ok(TimeInForce::PostOnly),
+            _ => Err(Error::Parse { offset: start, expected: "one of gtc ioc fok post" }),
+        }
+    }
+
+    // The last line may go without its newline
+    fn parse_end_of_line(&mut self) -> Result<()> {
+        match self.buf.get(self.i) {
+            None => Ok(()),
+            Some(&NEWLINE) => {
+                self.i += 1;
+                Ok(())
+            },
+            Some(_) => Err(Error::Parse { offset: self.i, expected: "newline" }),
+        }
+    }
+
+    fn parse_line(&mut self) -> Result<Command> {
+        let sign = self.buf[self.i];
+
+        if !matches!(sign, PLUS | BID | MINUS | EQUALS | DOLLAR | TILDE) {
+            return Err(Error::Parse { offset: self.i, expected: "one of + b - = $ ~" });
+        }
+        self.i += 1;
+        self.parse_space()?;
+
+        let num_a = self.parse_number()?;
+
+        let command = match sign {
+            PLUS | BID => {
+                self.parse_space()?;
+                let num_b = self.parse_number()?;
+                let side = if sign == PLUS { Side::Sell } else { Side::Buy };
+                let time_in_force = self.parse_time_in_force()?;
+
+                Command::Add { side, price: num_a, amount: num_b, time_in_force }
+            },
+            MINUS => Command::RemoveAt { side: Side::Sell, index: num_a },
+            DOLLAR => Command::TakeNotional { side: Side::Buy, budget: num_a },
+            TILDE => {
+                self.parse_space()?;
+                let num_b = self.parse_number()?;
+                self.parse_space()?;
+                let num_c = self.parse_number()?;
+
+                Command::Amend { id: num_a, price: num_b, amount: num_c }
+            },
+            _ => {
+                let limit = match self.buf.get(self.i) {
+                    Some(&SPACE) => {
+                        self.i += 1;
+                        Some(self.parse_number()?)
+                    },
+                    _ => None,
+                };
+
+                Command::Take { side: Side::Buy, amount: num_a, limit, mode: TakeMode::Partial }
+            },
+        };
+
+        self.parse_end_of_line()?;
+
+        Ok(command)
+    }
+
+    // Past next newline
+    fn skip_line(&mut self) {
+        match self.buf[self.i..].iter().position(|&c| c == NEWLINE) {
+            Some(newline) => self.i += newline + 1,
+            None => self.i = self.buf.len(),
+        }
+    }
+}
+
+impl Iterator for Parser<'_> {
+    type Item = Result<Command>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        // Blank lines are no-ops
+        while self.buf.get(self.i) == Some(&NEWLINE) {
+            self.i += 1;
+        }
+        if self.i >= self.buf.len() {
+            return None;
+        }
+
+        let command = self.parse_line();
+        if command.is_err() {
+            self.skip_line();
+        }
+
+        Some(command)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_parse_lines() {
+        let commands = Parser::new(b"+ 1137 100\nb 1130 10 ioc\n+ 1140 5 post\n- 0\n= 200\n= 50 1140\n$ 9000\n~ 3 1135 40\n")
+            .collect::<Result<Vec<Command>>>()
+            .unwrap();
+
+        assert_eq!(
+            vec![
+                Command::Add { side: Side::Sell, price: 1137, amount: 100, time_in_force: TimeInForce::GoodTillCancel },
+                Command::Add { side: Side::Buy, price: 1130, amount: 10, time_in_force: TimeInForce::ImmediateOrCancel },
+                Command::Add { side: Side::Sell, price: 1140, amount: 5, time_in_force: TimeInForce::PostOnly },
+                Command::RemoveAt { side: Side::Sell, index: 0 },
+                Command::Take { side: Side::Buy, amount: 200, limit: None, mode: TakeMode::Partial },
+                Command::Take { side: Side::Buy, amount: 50, limit: Some(1140), mode: TakeMode::Partial },
+                Command::TakeNotional { side: Side::Buy, budget: 9000 },
+                Command::Amend { id: 3, price: 1135, amount: 40 },
+            ],
+            commands
+        );
+    }
+
+    #[test]
+    fn test_last_line_without_newline() {
+        let commands = Parser::new(b"- 3\n\n= 20").collect::<Vec<_>>();
+
+        assert_eq!(
+            vec![
+                Ok(Command::RemoveAt { side: Side::Sell, index: 3 }),
+                Ok(Command::Take { side: Side::Buy, amount: 20, limit: None, mode: TakeMode::Partial }),
+            ],
+            commands
+        );
+    }
+
+    #[test]
+    fn test_bad_lines_are_skipped() {
+        let commands = Parser::new(b"* 1 2\n+ 12x 3\n- 1\n+ 1137").collect::<Vec<_>>();
+
+        assert_eq!(
+            vec![
+                Err(Error::Parse { offset: 0, expected: "one of + b - = $ ~" }),
+                Err(Error::Parse { offset: 10, expected: "space" }),
+                Ok(Command::RemoveAt { side: Side::Sell, index: 1 }),
+                // Truncated input runs out where the amount should be
+                Err(Error::Parse { offset: 24, expected: "space" }),
+            ],
+            commands
+        );
+
+        assert_eq!(
+            vec![Err(Error::Parse { offset: 7, expected: "one of gtc ioc fok post" })],
+            Parser::new(b"b 10 5 day\n").collect::<Vec<_>>()
+        );
+
+        assert_eq!(
+            vec![Err(Error::Parse { offset: 2, expected: "number below 2^64" })],
+            Parser::new(b"= 18446744073709551616\n").collect::<Vec<_>>()
+        );
+    }
+}
@@ -0,0 +1,334 @@
+// A counted treap: prices in order, each with a count, and every node knowing
+// the total count below it. Answers "which price holds the k-th unit" by
+// walking down once, O(log n) expected.
+
+const NIL: usize = usize::MAX;
+
+#[derive(Debug, Clone)]
+struct Node {
+    price: u64,
+    count: u64,
+    // count of this node and everything under it
+    sum: u64,
+    priority: u64,
+    left: usize,
+    right: usize,
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct CountedTree {
+    nodes: Vec<Node>,
+    free: Vec<usize>,
+    root: usize,
+    // xorshift state for node priorities, fixed seed so runs are repeatable
+    seed: u64,
+}
+
+impl Default for CountedTree {
+    fn default() -> Self {
+        Self {
+            nodes: Vec::new(),
+            free: Vec::new(),
+            root: NIL,
+            seed: 0x9e37_79b9_7f4a_7c15,
+        }
+    }
+}
+
+impl CountedTree {
+    #[cfg(test)]
+    fn total(&self) -> u64 {
+        self.sum(self.root)
+    }
+
+    /// Adds `by` to the count at `price`.
+    pub(crate) fn increment(&mut self, price: u64, by: u64) {
+        if !self.adjust(self.root, price, by as i64) {
+            let node = self.new_node(price, by);
+            self.root = self.insert(self.root, node);
+        }
+    }
+
+    /// Takes `by` off the count at `price`, dropping the price once it reaches 0.
+    pub(crate) fn decrement(&mut self, price: u64, by: u64) {
+        let found = self.adjust(self.root, price, -(by as i64));
+        debug_assert!(found, "No count for price.");
+
+        if self.count(price) == 0 {
+            self.root = self.erase(self.root, price);
+        }
+    }
+
+    /// The price holding the `index`-th unit and the unit's offset within it,
+    /// counting from the lowest price up or, when `descending`, from the
+    /// highest price down.
+    pub(crate) fn select(&self, mut index: u64, descending: bool) -> Option<(u64, u64)> {
+        let mut t = self.root;
+
+        while t != NIL {
+            let node = &self.nodes[t];
+            let (near, far) = if descending {
+                (node.right, node.left)
+            } else {
+                (node.left, node.right)
+            };
+
+            let near_sum = self.sum(near);
+            if index < near_sum {
+                t = near;
+            } else if index < near_sum + node.count {
+                return Some((node.price, index - near_sum));
+            } else {
+                index -= near_sum + node.count;
+                t = far;
+            }
+        }
+
+        None
+    }
+
+    fn count(&self, price: u64) -> u64 {
+        let mut t = self.root;
+
+        while t != NIL {
+            let node = &self.nodes[t];
+            if price == node.price {
+                return node.count;
+            }
+            t = if price < node.price { node.left } else { node.right };
+        }
+
+        0
+    }
+
+    fn sum(&self, t: usize) -> u64 {
+        if t == NIL {
+            0
+        } else {
+            self.nodes[t].sum
+        }
+    }
+
+    fn update(&mut self, t: usize) {
+        let (left, right) = (self.nodes[t].left, self.nodes[t].right);
+        self.nodes[t].sum = self.nodes[t].count + self.sum(left) + self.sum(right);
+    }
+
+    fn new_node(&mut self, price: u64, count: u64) -> usize {
+        self.seed ^= self.seed << 13;
+        self.seed ^= self.seed >> 7;
+        self.seed ^= self.seed << 17;
+
+        let node = Node {
+            price,
+            count,
+            sum: count,
+            priority: self.seed,
+            left: NIL,
+            right: NIL,
+        };
+
+        match self.free.pop() {
+            Some(t) => {
+                self.nodes[t] = node;
+                t
+            },
+            None => {
+                self.nodes.push(node);
+                self.nodes.len() - 1
+            }
+        }
+    }
+
+    // Changes the count at `price` in place, fixing sums on the way back up.
+    // Returns false if the price is not in the tree.
+    fn adjust(&mut self, t: usize, price: u64, delta: i64) -> bool {
+        if t == NIL {
+            return false;
+        }
+
+        let found = if price == self.nodes[t].price {
+            self.nodes[t].count = self.nodes[t].count.checked_add_signed(delta).expect("Count below zero.");
+            true
+        } else if price < self.nodes[t].price {
+            self.adjust(self.nodes[t].left, price, delta)
+        } else {
+            self.adjust(self.nodes[t].right, price, delta)
+        };
+
+        if found {
+            self.update(t);
+        }
+
+        found
+    }
+
+    // Splits into (prices below `price`, prices from `price` up)
+    fn split(&mut self, t: usize, price: u64) -> (usize, usize) {
+        if t == NIL {
+            return (NIL, NIL);
+        }
+
+        if self.nodes[t].price < price {
+            let (left, right) = self.split(self.nodes[t].right, price);
+            self.nodes[t].right = left;
+            self.update(t);
+            (t, right)
+        } else {
+            let (left, right) = self.split(self.nodes[t].left, price);
+            self.nodes[t].left = right;
+            self.update(t);
+            (left, t)
+        }
+    }
+
+    // Every price in `a` is below every price in `b`
+    fn merge(&mut self, a: usize, b: usize) -> usize {
+        if a == NIL {
+            return b;
+        }
+        if b == NIL {
+            return a;
+        }
+
+        if self.nodes[a].priority > self.nodes[b].priority {
+            self.nodes[a].right = self.merge(self.nodes[a].right, b);
+            self.update(a);
+            a
+        } else {
+            self.nodes[b].left = self.merge(a, self.nodes[b].left);
+            self.update(b);
+            b
+        }
+    }
+
+    fn insert(&mut self, t: usize, node: usize) -> usize {
+        if t == NIL {
+            return node;
+        }
+
+        if self.nodes[node].priority > self.nodes[t].priority {
+            let (left, right) = self.split(t, self.nodes[node].price);
+            self.nodes[node].left = left;
+            self.nodes[node].right = right;
+            self.update(node);
+            return node;
+        }
+
+        if self.nodes[node].price < self.nodes[t].price {
+            self.nodes[t].left = self.insert(self.nodes[t].left, node);
+        } else {
+            self.nodes[t].right = self.insert(self.nodes[t].right, node);
+        }
+        self.update(t);
+
+        t
+    }
+
+    fn erase(&mut self, t: usize, price: u64) -> usize {
+        if t == NIL {
+            return NIL;
+        }
+
+        if price == self.nodes[t].price {
+            self.free.push(t);
+            return self.merge(self.nodes[t].left, self.nodes[t].right);
+        }
+
+        if price < self.nodes[t].price {
+            self.nodes[t].left = self.erase(self.nodes[t].left, price);
+        } else {
+            self.nodes[t].right = self.erase(self.nodes[t].right, price);
+        }
+        self.update(t);
+
+        t
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use rand::Rng;
+    use std::collections::BTreeMap;
+
+    // The linear walk the tree replaces
+    fn select_by_walking(counts: &BTreeMap<u64, u64>, mut index: u64, descending: bool) -> Option<(u64, u64)> {
+        let levels: Box<dyn Iterator<Item = (&u64, &u64)>> = if descending {
+            Box::new(counts.iter().rev())
+        } else {
+            Box::new(counts.iter())
+        };
+
+        for (&price, &count) in levels {
+            if index < count {
+                return Some((price, index));
+            }
+            index -= count;
+        }
+
+        None
+    }
+
+    #[test]
+    fn test_select() {
+        let mut tree = CountedTree::default();
+
+        tree.increment(20, 3);
+        tree.increment(10, 2);
+        tree.increment(30, 1);
+
+        assert_eq!(6, tree.total());
+        assert_eq!(Some((10, 0)), tree.select(0, false));
+        assert_eq!(Some((20, 2)), tree.select(4, false));
+        assert_eq!(Some((30, 0)), tree.select(5, false));
+        assert_eq!(None, tree.select(6, false));
+
+        assert_eq!(Some((30, 0)), tree.select(0, true));
+        assert_eq!(Some((20, 0)), tree.select(1, true));
+        assert_eq!(Some((10, 1)), tree.select(5, true));
+
+        tree.decrement(20, 3);
+        assert_eq!(Some((30, 0)), tree.select(2, false));
+        assert_eq!(3, tree.total());
+    }
+
+    #[test]
+    fn test_against_linear_walk() {
+        let mut rng = rand::thread_rng();
+        let mut tree = CountedTree::default();
+        let mut counts: BTreeMap<u64, u64> = BTreeMap::new();
+
+        for _ in 0..10_000 {
+            let price = rng.gen_range(0..500);
+
+            match counts.get(&price).copied() {
+                Some(count) if rng.gen_bool(0.5) => {
+                    let by = rng.gen_range(1..=count);
+                    tree.decrement(price, by);
+                    if count == by {
+                        counts.remove(&price);
+                    } else {
+                        counts.insert(price, count - by);
+                    }
+                },
+                _ => {
+                    let by = rng.gen_range(1..5);
+                    tree.increment(price, by);
+                    *counts.entry(price).or_default() += by;
+                }
+            }
+
+            let total = counts.values().sum::<u64>();
+            assert_eq!(total, tree.total());
+
+            let index = rng.gen_range(0..=total);
+            assert_eq!(select_by_walking(&counts, index, false), tree.select(index, false));
+            assert_eq!(select_by_walking(&counts, index, true), tree.select(index, true));
+        }
+
+        // Freed nodes get reused rather than piling up
+        assert!(tree.nodes.len() <= 500);
+    }
+}
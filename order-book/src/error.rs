@@ -0,0 +1,91 @@
+use std::fmt;
+
+use crate::book::OrderId;
+
+/// Everything the book, the parser and the mmap helpers can reject.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Error {
+    /// Orders and takes must be for at least one unit
+    ZeroAmount,
+    /// No order rests at this position
+    IndexOutOfRange { index: u64 },
+    /// No order with this id rests on the book
+    UnknownOrder { id: OrderId },
+    /// Malformed command, `offset` is the byte where parsing gave up
+    Parse { offset: usize, expected: &'static str },
+    /// The book refused an order without touching anything
+    Rejected { reason: Reject },
+    /// A libc call failed
+    Os { call: &'static str, errno: i32 },
+    /// Reading or writing a file failed
+    Io { kind: std::io::ErrorKind },
+    /// A journal record that is damaged but not the last, so not left by a
+    /// crash part way through an append
+    CorruptJournal { offset: u64 },
+    /// A book snapshot that is damaged, cut short or of another format version
+    BadSnapshot { reason: &'static str },
+    /// A snapshot saved at journal sequence number `sequence` loaded over a
+    /// journal that only holds `records` records, so the two do not belong
+    /// together
+    SnapshotAheadOfJournal { sequence: u64, records: u64 },
+}
+
+/// Why `OrderBook::submit` turned an order away.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Reject {
+    /// A fill-or-kill order the opposite side cannot fill in full, or an
+    /// order that cannot trade its minimum quantity on arrival
+    NotEnoughLiquidity,
+    /// A post-only order priced to trade on arrival
+    WouldCross,
+    /// A pegged order whose reference price does not exist, such as a
+    /// midpoint peg while one side is empty
+    NoReference,
+    /// An all-or-none order with a peak, which would only ever show part of
+    /// what has to fill in one go
+    AllOrNoneIceberg,
+    /// A good-till-time or good-till-sequence order whose expiry has already
+    /// passed
+    Expired,
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::ZeroAmount => write!(f, "amount must be positive"),
+            Error::IndexOutOfRange { index } => write!(f, "no order at index {}", index),
+            Error::UnknownOrder { id } => write!(f, "no order with id {}", id),
+            Error::Parse { offset, expected } => write!(f, "expected {} at byte {}", expected, offset),
+            Error::Rejected { reason } => write!(f, "order rejected, {}", reason),
+            Error::Os { call, errno } => write!(f, "{} failed, errno {}", call, errno),
+            Error::Io { kind } => write!(f, "i/o failed, {}", kind),
+            Error::CorruptJournal { offset } => write!(f, "journal record at byte {} is damaged", offset),
+            Error::BadSnapshot { reason } => write!(f, "cannot load snapshot, {}", reason),
+            Error::SnapshotAheadOfJournal { sequence, records } => {
+                write!(f, "snapshot at sequence {} is ahead of a journal of {} records", sequence, records)
+            },
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<std::io::Error> for Error {
+    fn from(error: std::io::Error) -> Self {
+        Error::Io { kind: error.kind() }
+    }
+}
+
+impl fmt::Display for Reject {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Reject::NotEnoughLiquidity => write!(f, "not enough liquidity to fill in full"),
+            Reject::WouldCross => write!(f, "post-only order would cross"),
+            Reject::NoReference => write!(f, "nothing to peg to"),
+            Reject::AllOrNoneIceberg => write!(f, "all-or-none order cannot be an iceberg"),
+            Reject::Expired => write!(f, "order has already expired"),
+        }
+    }
+}
@@ -0,0 +1,799 @@
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Write};
+use std::path::Path;
+
+use crate::book::{
+    BookListener, NewOrder, OrderBook, Peg, PegReference, SelfTradePrevention, Side, Stop, TakeMode, TimeInForce,
+};
+use crate::clock::Clock;
+use crate::command::Command;
+use crate::error::{Error, Result};
+use crate::policy::MatchingPolicy;
+
+// Longest command encoding, a submit with every option set
+const MAX_COMMAND_LEN: usize = 64;
+// Length, sequence number and time in front of the command, checksum after it
+const RECORD_OVERHEAD: usize = 4 + 8 + 8 + 4;
+
+/// Appends every command the book accepts to a file before it runs, so a
+/// book lost in a crash can be rebuilt by replaying the file.
+///
+/// Each record is the length of the command, a sequence number counting up
+/// from 0, the time by the book's clock the command ran at, the command and a
+/// CRC-32 of the sequence number, time and command, all little-endian. A
+/// crash part way through an append leaves a torn record at the end, which
+/// `open` cuts off.
+#[derive(Debug)]
+pub struct Journal {
+    file: File,
+    // Bytes of whole records in the file
+    len: u64,
+    next_sequence: u64,
+    torn_len: u64,
+}
+
+impl Journal {
+    /// Opens the journal at `path`, creating it if it is not there, and
+    /// replays every record in it into `order_book`.
+    ///
+    /// Each command runs with the book's clock pinned at the time it first
+    /// ran at, so the book comes back exactly as it was. A damaged record with
+    /// more records after it cannot be a torn append and fails with
+    /// `Error::CorruptJournal`, leaving the file as it was.
+    pub fn open<P: MatchingPolicy, C: Clock>(
+        path: impl AsRef<Path>,
+        order_book: &mut OrderBook<P, C>
+    ) -> Result<Self> {
+        Self::open_from(path, order_book, 0)
+    }
+
+    /// Like `open`, but only replays the records from sequence number
+    /// `sequence` on, for a book loaded from a snapshot saved with it.
+    ///
+    /// Earlier records are still checked, so damage in them is found all the
+    /// same. A `sequence` past the last record fails with
+    /// `Error::SnapshotAheadOfJournal`, since the snapshot holds commands the
+    /// journal has lost or was never given, leaving the book and file as they
+    /// were.
+    pub fn open_from<P: MatchingPolicy, C: Clock>(
+        path: impl AsRef<Path>,
+        order_book: &mut OrderBook<P, C>,
+        sequence: u64
+    ) -> Result<Self> {
+        let mut file = OpenOptions::new().read(true).append(true).create(true).open(path)?;
+        let mut buf = Vec::new();
+        file.read_to_end(&mut buf)?;
+
+        let mut commands = Vec::new();
+        let mut offset = 0;
+        while offset < buf.len() {
+            match decode_record(&buf[offset..], commands.len() as u64) {
+                Ok((command, now, record_len)) => {
+                    commands.push((command, now));
+                    offset += record_len;
+                },
+                Err(Damage { record_len }) => {
+                    // Only the last append can have been cut short, leaving
+                    // part of one record, or zeros where the file grew but
+                    // the write never landed. A length field too damaged to
+                    // read says nothing, so then the record only counts as
+                    // torn with less than the longest record left.
+                    let tail = &buf[offset..];
+                    let torn = match record_len {
+                        Some(record_len) => tail.len() <= record_len,
+                        None => tail.len() < MAX_COMMAND_LEN + RECORD_OVERHEAD,
+                    };
+                    if !torn && tail.iter().any(|&byte| byte != 0) {
+                        return Err(Error::CorruptJournal { offset: offset as u64 });
+                    }
+                    break;
+                },
+            }
+        }
+
+        let records = commands.len() as u64;
+        if sequence > records {
+            return Err(Error::SnapshotAheadOfJournal { sequence, records });
+        }
+
+        let torn_len = (buf.len() - offset) as u64;
+        if torn_len > 0 {
+            file.set_len(offset as u64)?;
+        }
+
+        // A record left by a crash before a turned down command could be
+        // taken back out is turned down again
+        for &(command, now) in commands.iter().skip(sequence as usize) {
+            let _ = order_book.apply_at(command, now);
+        }
+
+        Ok(Self {
+            file,
+            len: offset as u64,
+            next_sequence: records,
+            torn_len,
+        })
+    }
+
+    /// Writes `command`, run at time `now`, to the end of the journal in one
+    /// write, returning its sequence number.
+    ///
+    /// The record reaches the operating system before this returns, `sync`
+    /// makes it to disk. A write that fails part way, on a full disk say, is
+    /// cut back off so the next record follows the last whole one.
+    pub fn append(&mut self, command: Command, now: u64) -> Result<u64> {
+        let sequence = self.next_sequence;
+
+        // The length goes in front once the command is encoded
+        let mut record = Encoder::new();
+        record.put_u32(0);
+        record.put_u64(sequence);
+        record.put_u64(now);
+        encode_command(&mut record, command);
+
+        let command_len = record.len - 4 - 8 - 8;
+        record.buf[..4].copy_from_slice(&(command_len as u32).to_le_bytes());
+        let crc = crc32(&record.buf[4..record.len]);
+        record.put_u32(crc);
+
+        if let Err(error) = self.file.write_all(&record.buf[..record.len]) {
+            self.file.set_len(self.len)?;
+            return Err(error.into());
+        }
+        self.len += record.len as u64;
+        self.next_sequence += 1;
+
+        Ok(sequence)
+    }
+
+    /// Appends `command`, then runs it against `order_book` at the time the
+    /// record gives.
+    ///
+    /// A command the book turns down is cut back off the end of the journal,
+    /// so its sequence number goes to the next command.
+    pub fn apply<P: MatchingPolicy, C: Clock>(
+        &mut self,
+        order_book: &mut OrderBook<P, C>,
+        command: Command
+    ) -> Result<()> {
+        self.apply_into(order_book, command, &mut ())
+    }
+
+    /// Same as `apply`, reporting every change to the book to `listener`.
+    pub fn apply_into<P: MatchingPolicy, C: Clock>(
+        &mut self,
+        order_book: &mut OrderBook<P, C>,
+        command: Command,
+        listener: &mut impl BookListener
+    ) -> Result<()> {
+        let len = self.len;
+        let now = order_book.now();
+        self.append(command, now)?;
+
+        if let Err(error) = order_book.apply_at_into(command, now, listener) {
+            self.file.set_len(len)?;
+            self.len = len;
+            self.next_sequence -= 1;
+            return Err(error);
+        }
+
+        Ok(())
+    }
+
+    /// Waits for every appended record to reach the disk.
+    pub fn sync(&self) -> Result<()> {
+        self.file.sync_data()?;
+
+        Ok(())
+    }
+
+    /// Sequence number the next append gets, also the number of records.
+    pub fn next_sequence(&self) -> u64 {
+        self.next_sequence
+    }
+
+    /// Bytes of a torn last record `open` cut off, 0 if there was none.
+    pub fn torn_len(&self) -> u64 {
+        self.torn_len
+    }
+}
+
+// A record that does not check out, with its length if the length field was
+// whole and in range
+struct Damage {
+    record_len: Option<usize>,
+}
+
+// The command at the front of `buf`, the time it ran at and the length of its
+// record
+fn decode_record(buf: &[u8], sequence: u64) -> std::result::Result<(Command, u64, usize), Damage> {
+    let mut decoder = Decoder { buf };
+    let command_len = decoder.take_u32().ok_or(Damage { record_len: None })? as usize;
+    if command_len > MAX_COMMAND_LEN {
+        return Err(Damage { record_len: None });
+    }
+
+    let record_len = command_len + RECORD_OVERHEAD;
+    let damage = Damage { record_len: Some(record_len) };
+    if buf.len() < record_len {
+        return Err(damage);
+    }
+
+    let crc = u32::from_le_bytes(buf[record_len - 4..record_len].try_into().unwrap());
+    if crc != crc32(&buf[4..record_len - 4]) {
+        return Err(damage);
+    }
+
+    let mut decoder = Decoder { buf: &buf[4..record_len - 4] };
+    if decoder.take_u64() != Some(sequence) {
+        return Err(damage);
+    }
+    let Some(now) = decoder.take_u64() else {
+        return Err(damage);
+    };
+    match decode_command(&mut decoder) {
+        Some(command) if decoder.buf.is_empty() => Ok((command, now, record_len)),
+        _ => Err(damage),
+    }
+}
+
+struct Encoder {
+    buf: [u8; MAX_COMMAND_LEN + RECORD_OVERHEAD],
+    len: usize,
+}
+
+impl Encoder {
+    fn new() -> Self {
+        Self {
+            buf: [0; MAX_COMMAND_LEN + RECORD_OVERHEAD],
+            len: 0,
+        }
+    }
+
+    fn put_u8(&mut self, value: u8) {
+        self.buf[self.len] = value;
+        self.len += 1;
+    }
+
+    fn put_u32(&mut self, value: u32) {
+        self.buf[self.len..self.len + 4].copy_from_slice(&value.to_le_bytes());
+        self.len += 4;
+    }
+
+    fn put_u64(&mut self, value: u64) {
+        self.buf[self.len..self.len + 8].copy_from_slice(&value.to_le_bytes());
+        self.len += 8;
+    }
+
+    // A 0 or 1 tag, then the value when there is one
+    fn put_option(&mut self, value: Option<u64>) {
+        match value {
+            Some(value) => {
+                self.put_u8(1);
+                self.put_u64(value);
+            },
+            None => self.put_u8(0),
+        }
+    }
+}
+
+struct Decoder<'a> {
+    buf: &'a [u8],
+}
+
+impl Decoder<'_> {
+    fn take<const N: usize>(&mut self) -> Option<[u8; N]> {
+        let (bytes, rest) = self.buf.split_first_chunk::<N>()?;
+        self.buf = rest;
+
+        Some(*bytes)
+    }
+
+    fn take_u8(&mut self) -> Option<u8> {
+        self.take::<1>().map(|[value]| value)
+    }
+
+    fn take_u32(&mut self) -> Option<u32> {
+        self.take().map(u32::from_le_bytes)
+    }
+
+    fn take_u64(&mut self) -> Option<u64> {
+        self.take().map(u64::from_le_bytes)
+    }
+
+    fn take_option(&mut self) -> Option<Option<u64>> {
+        match self.take_u8()? {
+            0 => Some(None),
+            1 => self.take_u64().map(Some),
+            _ => None,
+        }
+    }
+}
+
+fn encode_side(encoder: &mut Encoder, side: Side) {
+    encoder.put_u8(match side {
+        Side::Buy => 0,
+        Side::Sell => 1,
+    });
+}
+
+fn decode_side(decoder: &mut Decoder) -> Option<Side> {
+    match decoder.take_u8()? {
+        0 => Some(Side::Buy),
+        1 => Some(Side::Sell),
+        _ => None,
+    }
+}
+
+fn encode_time_in_force(encoder: &mut Encoder, time_in_force: TimeInForce) {
+    match time_in_force {
+        TimeInForce::GoodTillCancel => encoder.put_u8(0),
+        TimeInForce::ImmediateOrCancel => encoder.put_u8(1),
+        TimeInForce::FillOrKill => encoder.put_u8(2),
+        TimeInForce::PostOnly => encoder.put_u8(3),
+        TimeInForce::GoodTillTime(time) => {
+            encoder.put_u8(4);
+            encoder.put_u64(time);
+        },
+        TimeInForce::GoodTillSequence(sequence) => {
+            encoder.put_u8(5);
+            encoder.put_u64(sequence);
+        },
+    }
+}
+
+fn decode_time_in_force(decoder: &mut Decoder) -> Option<TimeInForce> {
+    let time_in_force = match decoder.take_u8()? {
+        0 => TimeInForce::GoodTillCancel,
+        1 => TimeInForce::ImmediateOrCancel,
+        2 => TimeInForce::FillOrKill,
+        3 => TimeInForce::PostOnly,
+        4 => TimeInForce::GoodTillTime(decoder.take_u64()?),
+        5 => TimeInForce::GoodTillSequence(decoder.take_u64()?),
+        _ => return None,
+    };
+
+    Some(time_in_force)
+}
+
+// 0 without a peg, otherwise 1 plus the reference, then the offset
+fn encode_peg(encoder: &mut Encoder, peg: Option<Peg>) {
+    let Some(peg) = peg else {
+        encoder.put_u8(0);
+        return;
+    };
+
+    encoder.put_u8(match peg.reference {
+        PegReference::BestBid => 1,
+        PegReference::BestAsk => 2,
+        PegReference::Midpoint => 3,
+    });
+    encoder.put_u64(peg.offset as u64);
+}
+
+fn decode_peg(decoder: &mut Decoder) -> Option<Option<Peg>> {
+    let reference = match decoder.take_u8()? {
+        0 => return Some(None),
+        1 => PegReference::BestBid,
+        2 => PegReference::BestAsk,
+        3 => PegReference::Midpoint,
+        _ => return None,
+    };
+
+    Some(Some(Peg { reference, offset: decoder.take_u64()? as i64 }))
+}
+
+fn encode_command(encoder: &mut Encoder, command: Command) {
+    match command {
+        Command::Add { side, price, amount, time_in_force } => {
+            encoder.put_u8(0);
+            encode_side(encoder, side);
+            encoder.put_u64(price);
+            encoder.put_u64(amount);
+            encode_time_in_force(encoder, time_in_force);
+        },
+        Command::RemoveAt { side, index } => {
+            encoder.put_u8(1);
+            encode_side(encoder, side);
+            encoder.put_u64(index);
+        },
+        Command::Take { side, amount, limit, mode } => {
+            encoder.put_u8(2);
+            encode_side(encoder, side);
+            encoder.put_u64(amount);
+            match limit {
+                Some(limit) => {
+                    encoder.put_u8(1);
+                    encoder.put_u64(limit);
+                },
+                None => encoder.put_u8(0),
+            }
+            encoder.put_u8(match mode {
+                TakeMode::Partial => 0,
+                TakeMode::FillOrKill => 1,
+            });
+        },
+        Command::TakeNotional { side, budget } => {
+            encoder.put_u8(3);
+            encode_side(encoder, side);
+            encoder.put_u64(budget);
+        },
+        Command::Amend { id, price, amount } => {
+            encoder.put_u8(4);
+            encoder.put_u64(id);
+            encoder.put_u64(price);
+            encoder.put_u64(amount);
+        },
+        Command::Cancel { id } => {
+            encoder.put_u8(5);
+            encoder.put_u64(id);
+        },
+        Command::Submit(order) => {
+            encoder.put_u8(6);
+            encode_side(encoder, order.side);
+            encoder.put_u64(order.price);
+            encoder.put_u64(order.amount);
+            encode_time_in_force(encoder, order.time_in_force);
+            encoder.put_option(order.peak);
+            encode_peg(encoder, order.peg);
+            encoder.put_option(order.owner);
+            encoder.put_option(order.min_quantity);
+            encoder.put_u8(order.all_or_none as u8);
+        },
+        Command::SubmitStop(stop) => {
+            encoder.put_u8(7);
+            encode_side(encoder, stop.side);
+            encoder.put_u64(stop.trigger);
+            encoder.put_u64(stop.amount);
+            encoder.put_option(stop.limit);
+            encoder.put_option(stop.owner);
+        },
+        Command::SetSelfTradePrevention(self_trade_prevention) => {
+            encoder.put_u8(8);
+            encoder.put_u8(match self_trade_prevention {
+                SelfTradePrevention::CancelResting => 0,
+                SelfTradePrevention::CancelIncoming => 1,
+                SelfTradePrevention::CancelBoth => 2,
+                SelfTradePrevention::DecrementBoth => 3,
+            });
+        }
+    }
+}
+
+fn decode_command(decoder: &mut Decoder) -> Option<Command> {
+    let command = match decoder.take_u8()? {
+        0 => {
+            let side = decode_side(decoder)?;
+            let price = decoder.take_u64()?;
+            let amount = decoder.take_u64()?;
+            let time_in_force = decode_time_in_force(decoder)?;
+
+            Command::Add { side, price, amount, time_in_force }
+        },
+        1 => Command::RemoveAt { side: decode_side(decoder)?, index: decoder.take_u64()? },
+        2 => {
+            let side = decode_side(decoder)?;
+            let amount = decoder.take_u64()?;
+            let limit = match decoder.take_u8()? {
+                0 => None,
+                1 => Some(decoder.take_u64()?),
+                _ => return None,
+            };
+            let mode = match decoder.take_u8()? {
+                0 => TakeMode::Partial,
+                1 => TakeMode::FillOrKill,
+                _ => return None,
+            };
+
+            Command::Take { side, amount, limit, mode }
+        },
+        3 => Command::TakeNotional { side: decode_side(decoder)?, budget: decoder.take_u64()? },
+        4 => Command::Amend { id: decoder.take_u64()?, price: decoder.take_u64()?, amount: decoder.take_u64()? },
+        5 => Command::Cancel { id: decoder.take_u64()? },
+        6 => Command::Submit(NewOrder {
+            side: decode_side(decoder)?,
+            price: decoder.take_u64()?,
+            amount: decoder.take_u64()?,
+            time_in_force: decode_time_in_force(decoder)?,
+            peak: decoder.take_option()?,
+            peg: decode_peg(decoder)?,
+            owner: decoder.take_option()?,
+            min_quantity: decoder.take_option()?,
+            all_or_none: match decoder.take_u8()? {
+                0 => false,
+                1 => true,
+                _ => return None,
+            },
+        }),
+        7 => Command::SubmitStop(Stop {
+            side: decode_side(decoder)?,
+            trigger: decoder.take_u64()?,
+            amount: decoder.take_u64()?,
+            limit: decoder.take_option()?,
+            owner: decoder.take_option()?,
+        }),
+        8 => Command::SetSelfTradePrevention(match decoder.take_u8()? {
+            0 => SelfTradePrevention::CancelResting,
+            1 => SelfTradePrevention::CancelIncoming,
+            2 => SelfTradePrevention::CancelBoth,
+            3 => SelfTradePrevention::DecrementBoth,
+            _ => return None,
+        }),
+        _ => return None,
+    };
+
+    Some(command)
+}
+
+// CRC-32 as zip and ethernet use it, reflected polynomial 0xedb88320
+const CRC_TABLE: [u32; 256] = {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 == 1 { (crc >> 1) ^ 0xedb8_8320 } else { crc >> 1 };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+pub(crate) fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in bytes {
+        crc = CRC_TABLE[((crc ^ byte as u32) & 0xff) as usize] ^ (crc >> 8);
+    }
+
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::book::Fill;
+    use crate::error::Reject;
+    use crate::clock::ManualClock;
+    use crate::policy::Fifo;
+    use std::path::PathBuf;
+
+    // A fresh file under the temp directory, gone when dropped
+    struct TempPath(PathBuf);
+
+    impl TempPath {
+        fn new(name: &str) -> Self {
+            let path = std::env::temp_dir().join(format!("order-book-{}-{}", std::process::id(), name));
+            let _ = std::fs::remove_file(&path);
+            Self(path)
+        }
+    }
+
+    impl Drop for TempPath {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+        }
+    }
+
+    fn commands() -> Vec<Command> {
+        vec![
+            Command::Add { side: Side::Sell, price: 10, amount: 5, time_in_force: TimeInForce::GoodTillCancel },
+            Command::Add { side: Side::Sell, price: 11, amount: 3, time_in_force: TimeInForce::GoodTillSequence(9) },
+            Command::Add { side: Side::Buy, price: 9, amount: 4, time_in_force: TimeInForce::GoodTillTime(u64::MAX) },
+            Command::SetSelfTradePrevention(SelfTradePrevention::CancelResting),
+            Command::Submit(NewOrder { peak: Some(1), owner: Some(7), ..NewOrder::limit(Side::Sell, 12, 4) }),
+            Command::Take { side: Side::Buy, amount: 2, limit: Some(10), mode: TakeMode::FillOrKill },
+            Command::Amend { id: 1, price: 12, amount: 2 },
+            // Turned down by the book, so never kept
+            Command::Cancel { id: 7 },
+            Command::SubmitStop(Stop { side: Side::Buy, trigger: 12, amount: 1, limit: Some(13), owner: Some(7) }),
+            // Every option set, which makes an all-or-none iceberg the book turns down
+            Command::Submit(NewOrder {
+                time_in_force: TimeInForce::GoodTillSequence(u64::MAX),
+                peak: Some(1),
+                peg: Some(Peg { reference: PegReference::Midpoint, offset: -3 }),
+                owner: Some(7),
+                min_quantity: Some(2),
+                all_or_none: true,
+                ..NewOrder::limit(Side::Buy, 1, 2)
+            }),
+            Command::TakeNotional { side: Side::Buy, budget: 20 },
+            Command::RemoveAt { side: Side::Buy, index: 0 },
+        ]
+    }
+
+    #[test]
+    fn test_crc32() {
+        assert_eq!(0xcbf4_3926, crc32(b"123456789"));
+        assert_eq!(0, crc32(b""));
+    }
+
+    #[test]
+    fn test_commands_round_trip() {
+        let mut longest = 0;
+        for command in commands() {
+            let mut encoder = Encoder::new();
+            encode_command(&mut encoder, command);
+            longest = longest.max(encoder.len);
+
+            let mut decoder = Decoder { buf: &encoder.buf[..encoder.len] };
+            assert_eq!(Some(command), decode_command(&mut decoder));
+        }
+        assert_eq!(MAX_COMMAND_LEN, longest);
+    }
+
+    #[test]
+    fn test_replay_rebuilds_the_book() {
+        let path = TempPath::new("replay");
+        let mut order_book = OrderBook::new();
+
+        let mut journal = Journal::open(&path.0, &mut order_book).unwrap();
+        for command in commands() {
+            let _ = journal.apply(&mut order_book, command);
+        }
+        assert_eq!(10, journal.next_sequence());
+        drop(journal);
+
+        let mut replayed = OrderBook::new();
+        let journal = Journal::open(&path.0, &mut replayed).unwrap();
+        assert_eq!((10, 0), (journal.next_sequence(), journal.torn_len()));
+        assert_eq!(order_book.l3_snapshot(), replayed.l3_snapshot());
+        assert_eq!((1, order_book.last_price()), (replayed.stop_count(), replayed.last_price()));
+    }
+
+    #[test]
+    fn test_replay_runs_at_the_recorded_time() {
+        let path = TempPath::new("time");
+        let clock = ManualClock::new(100);
+        let mut order_book = OrderBook::with_clock(Fifo, clock.clone());
+        let mut journal = Journal::open(&path.0, &mut order_book).unwrap();
+        let take = |amount| Command::Take { side: Side::Buy, amount, limit: None, mode: TakeMode::Partial };
+
+        let good_till_time = TimeInForce::GoodTillTime(150);
+        journal.apply(&mut order_book, Command::Add { side: Side::Sell, price: 10, amount: 5, time_in_force: good_till_time })
+            .unwrap();
+        journal.apply(&mut order_book, Command::Add { side: Side::Sell, price: 11, amount: 2, time_in_force: TimeInForce::GoodTillCancel })
+            .unwrap();
+        clock.set(120);
+        let mut fills = Vec::new();
+        journal.apply_into(&mut order_book, take(2), &mut fills).unwrap();
+        assert_eq!(vec![Fill { maker_id: 0, price: 10, amount: 2, maker_remaining: 3 }], fills);
+        clock.set(200);
+        journal.apply(&mut order_book, take(1)).unwrap();
+
+        // Turned down, and gone from the file again
+        let len = std::fs::metadata(&path.0).unwrap().len();
+        assert_eq!(Err(Error::UnknownOrder { id: 9 }), journal.apply(&mut order_book, Command::Cancel { id: 9 }));
+        let fill_or_kill = Command::Take { side: Side::Buy, amount: 9, limit: None, mode: TakeMode::FillOrKill };
+        let not_enough = Err(Error::Rejected { reason: Reject::NotEnoughLiquidity });
+        assert_eq!(not_enough, journal.apply(&mut order_book, fill_or_kill));
+        assert_eq!((4, len), (journal.next_sequence(), std::fs::metadata(&path.0).unwrap().len()));
+        drop(journal);
+
+        // Long after the good-till-time order expired, the replay still sees
+        // it rest and trade
+        let mut replayed = OrderBook::with_clock(Fifo, ManualClock::new(1_000));
+        Journal::open(&path.0, &mut replayed).unwrap();
+        assert_eq!(order_book.l3_snapshot(), replayed.l3_snapshot());
+        assert_eq!(1, replayed.depth_at(Side::Sell, 11));
+    }
+
+    #[test]
+    fn test_torn_record_is_cut_off() {
+        let path = TempPath::new("torn");
+        let mut journal = Journal::open(&path.0, &mut OrderBook::new()).unwrap();
+        journal.append(commands()[0], 0).unwrap();
+        let whole_len = std::fs::metadata(&path.0).unwrap().len();
+        journal.append(commands()[1], 0).unwrap();
+        drop(journal);
+
+        // The second record loses its checksum
+        let file = OpenOptions::new().write(true).open(&path.0).unwrap();
+        file.set_len(whole_len + 10).unwrap();
+        drop(file);
+
+        let mut order_book = OrderBook::new();
+        let mut journal = Journal::open(&path.0, &mut order_book).unwrap();
+        assert_eq!((1, 10), (journal.next_sequence(), journal.torn_len()));
+        assert_eq!(1, order_book.order_count());
+
+        // Appends carry on from the cut
+        assert_eq!(1, journal.append(commands()[1], 0).unwrap());
+        drop(journal);
+        let mut order_book = OrderBook::new();
+        assert_eq!(2, Journal::open(&path.0, &mut order_book).unwrap().next_sequence());
+        assert_eq!(2, order_book.order_count());
+    }
+
+    #[test]
+    fn test_damage_before_the_end_is_an_error() {
+        let path = TempPath::new("corrupt");
+        let mut journal = Journal::open(&path.0, &mut OrderBook::new()).unwrap();
+        journal.append(commands()[0], 0).unwrap();
+        journal.append(commands()[1], 0).unwrap();
+        drop(journal);
+
+        let mut bytes = std::fs::read(&path.0).unwrap();
+        bytes[14] ^= 1;
+        std::fs::write(&path.0, &bytes).unwrap();
+
+        assert_eq!(Err(Error::CorruptJournal { offset: 0 }), Journal::open(&path.0, &mut OrderBook::new()).map(|_| ()));
+        assert_eq!(bytes.len() as u64, std::fs::metadata(&path.0).unwrap().len());
+    }
+
+    #[test]
+    fn test_restart_from_a_snapshot() {
+        let path = TempPath::new("snapshot");
+        let mut order_book = OrderBook::new();
+        let mut journal = Journal::open(&path.0, &mut order_book).unwrap();
+        let mut snapshot = Vec::new();
+
+        for (index, command) in commands().into_iter().enumerate() {
+            if index == 4 {
+                order_book.save_snapshot(journal.next_sequence(), &mut snapshot).unwrap();
+            }
+            let _ = journal.apply(&mut order_book, command);
+        }
+        drop(journal);
+
+        let mut restarted = OrderBook::new();
+        let sequence = restarted.load_snapshot(&mut &snapshot[..]).unwrap();
+        let journal = Journal::open_from(&path.0, &mut restarted, sequence).unwrap();
+        assert_eq!((4, 10), (sequence, journal.next_sequence()));
+        assert_eq!(order_book.l3_snapshot(), restarted.l3_snapshot());
+    }
+
+    #[test]
+    fn test_snapshot_ahead_of_the_journal() {
+        let path = TempPath::new("ahead");
+        let mut order_book = OrderBook::new();
+        let mut journal = Journal::open(&path.0, &mut order_book).unwrap();
+        journal.apply(&mut order_book, commands()[0]).unwrap();
+        drop(journal);
+
+        // Saved as though two more records had been written
+        let mut snapshot = Vec::new();
+        order_book.save_snapshot(3, &mut snapshot).unwrap();
+        let len = std::fs::metadata(&path.0).unwrap().len();
+
+        let mut restarted = OrderBook::new();
+        let sequence = restarted.load_snapshot(&mut &snapshot[..]).unwrap();
+        assert_eq!(
+            Err(Error::SnapshotAheadOfJournal { sequence: 3, records: 1 }),
+            Journal::open_from(&path.0, &mut restarted, sequence).map(|_| ())
+        );
+        assert_eq!(len, std::fs::metadata(&path.0).unwrap().len());
+        assert_eq!(order_book.l3_snapshot(), restarted.l3_snapshot());
+
+        // Caught up with, the two fit together again
+        assert_eq!(1, Journal::open_from(&path.0, &mut restarted, 1).unwrap().next_sequence());
+    }
+
+    #[test]
+    fn test_damaged_length_is_not_a_torn_record() {
+        let path = TempPath::new("length");
+        let mut journal = Journal::open(&path.0, &mut OrderBook::new()).unwrap();
+        for command in &commands()[..5] {
+            journal.append(*command, 0).unwrap();
+        }
+        drop(journal);
+
+        // The first length now runs past the longest record there can be
+        let mut bytes = std::fs::read(&path.0).unwrap();
+        bytes[0] ^= 0x80;
+        std::fs::write(&path.0, &bytes).unwrap();
+
+        assert_eq!(Err(Error::CorruptJournal { offset: 0 }), Journal::open(&path.0, &mut OrderBook::new()).map(|_| ()));
+        assert_eq!(bytes.len() as u64, std::fs::metadata(&path.0).unwrap().len());
+
+        // Zeros where the last append should be are a torn record all the same
+        bytes[0] ^= 0x80;
+        bytes.extend_from_slice(&[0; 100]);
+        std::fs::write(&path.0, &bytes).unwrap();
+        let journal = Journal::open(&path.0, &mut OrderBook::new()).unwrap();
+        assert_eq!((5, 100), (journal.next_sequence(), journal.torn_len()));
+    }
+}
@@ -1,105 +1,86 @@
 #![feature(test)]
 
-use std::collections::{BTreeMap, VecDeque};
-use std::time::Instant;
+pub mod book;
 pub mod btree;
+pub mod clock;
+pub mod command;
+mod counted;
+pub mod error;
+pub mod journal;
+pub mod policy;
+pub mod snapshot;
+
+pub use book::{
+    Added, BookEvent, BookListener, Fill, FillSink, Level, NewOrder, Order, OrderBook, OrderId, OwnerId, Peg,
+    PegReference, Quote, SelfTradePrevention, Side, Spent, Stop, TakeMode, Taken, TimeInForce,
+};
+pub use clock::{Clock, ManualClock, SystemClock};
+pub use command::{Command, Parser};
+pub use error::{Error, Reject, Result};
+pub use journal::Journal;
+pub use policy::{Fifo, MatchingPolicy, ProRata, TopOrderProRata};
+pub use snapshot::{L2Snapshot, L3Snapshot, LevelSnapshot, OrderSnapshot};
 
-const PLUS: u8 = 0x2b;
-const MINUS: u8 = 0x2d;
-const EQUALS: u8 = 0x3d;
-const SPACE: u8 = 0x20;
-const NEWLINE: u8 = 0x0a;
+#[cfg(test)]
+use command::{EQUALS, MINUS, NEWLINE, SPACE};
 
+#[cfg(test)]
 enum State {
     ParseSign,
     ParseNumA,
     ParseNumB
 }
 
-fn run_for_benchmark_by_line() {
-    // let buf = unsafe { mmap_stdin() };
-    let buf: &[u8] = "+ 1137 100\n+ 1130 10\n+ 1130 50\n- 0\n+ 1150 200\n= 200\n".as_bytes();
-
-    let buf_len = buf.len();
-    // BTreeMap of an LinkedList (IndexMap does not allow for the same size value!)
-    // Price -> Sorted Orders
-
-    // Maybe use a vecdeque we search on removal?
-    // Delay sorting
-    let mut price_to_order_queue: BTreeMap<u64, VecDeque<u64>> = BTreeMap::new();
-
-    let mut sign: u8 = 0;
-    let mut num_a: u64 = 0;
-    let mut num_b: u64= 0;
+/// Applies every line in `buf` to `order_book`, stopping at the first line
+/// that fails to parse or that the book rejects.
+///
+/// `+ price amount [tif]` sells and `b price amount [tif]` buys at a limit,
+/// where `tif` is one of `gtc`, `ioc`, `fok` or `post`. `- index` removes the
+/// ask at that position, `= amount [limit]` buys from the asks, `$ budget`
+/// buys from the asks for at most `budget` and `~ id price amount` amends a
+/// resting order.
+pub fn run_by_line(order_book: &mut OrderBook, buf: &[u8]) -> Result<()> {
+    run_by_line_at_intervals(order_book, buf, 0, |_, _| ())
+}
 
-    // matching every character, state
-    // takes too long to long to parse the integers
-    // we can parse line by line very quickly
-    // we need to keep a pointer of sorts tho
-    // also we can disqualify if num_b == 0 since that's a no op
-    // so for each integer, iterate and build it up BUT
-    // we also check every stage that the char is in our desired range 0x30 to 0x39
-    // we break if it isn't
-
-    let mut i = 0;
-
-    while i < buf_len {
-        // println!("i: {}", i);
-        
-        sign = buf[i];
-        i += 2;
-        num_a = 0;
-        while buf[i] > 0x29 && buf[i] < 0x40 {
-            num_a *= 10;
-            num_a += (buf[i] - 0x30) as u64;
-            i += 1;
+/// Same as `run_by_line`, handing the book to `at_interval` after every
+/// `interval` commands along with how many have run, for taking snapshots
+/// as a file replays. An `interval` of 0 never calls it.
+pub fn run_by_line_at_intervals(
+    order_book: &mut OrderBook,
+    buf: &[u8],
+    interval: usize,
+    mut at_interval: impl FnMut(usize, &OrderBook)
+) -> Result<()> {
+    for (index, command) in Parser::new(buf).enumerate() {
+        order_book.apply(command?)?;
+
+        let applied_count = index + 1;
+        if interval > 0 && applied_count % interval == 0 {
+            at_interval(applied_count, order_book);
         }
+    }
 
-        // println!("1 buf[i]: {:x}", buf[i]);
-        
-        if buf[i] == SPACE {
-            i += 1;
-            num_b = 0;
-            while buf[i] > 0x29 && buf[i] < 0x40 {
-                num_b *= 10;
-                num_b += (buf[i] - 0x30) as u64;
-                i += 1;
-            }
-        }
+    Ok(())
+}
 
-        // println!("2 buf[i]: {:x}", buf[i]);
-        // Past next newline
-        i += 1;
+#[cfg(test)]
+fn run_for_benchmark_by_line() {
+    // let buf = unsafe { mmap_stdin() };
+    let buf: &[u8] = "+ 1137 100\n+ 1130 10\n+ 1130 50\n- 0\n+ 1150 200\n= 200\n".as_bytes();
 
-        // println!("{} {} {}", sign as char, num_a, num_b);
+    let mut order_book = OrderBook::new();
 
-        match sign {
-            PLUS => {
-                add_liquidity(&mut price_to_order_queue, num_a, num_b);
-            },
-            MINUS => {
-                remove_order(&mut price_to_order_queue, num_a);
-            },
-            EQUALS => {
-                take_liquidity(&mut price_to_order_queue, num_a);
-            },
-            _ => ()
-        }
-    }
-
-    // panic!("WEEE");
+    run_by_line(&mut order_book, buf).unwrap();
 }
 
+#[cfg(test)]
 fn run_for_benchmark_by_char() {
 
     // let buf = unsafe { mmap_stdin() };
     let buf: &[u8] = "+ 1137 100\n+ 1130 10\n+ 1130 50\n- 0\n+ 1150 200\n= 200\n".as_bytes();
 
-    // BTreeMap of an LinkedList (IndexMap does not allow for the same size value!)
-    // Price -> Sorted Orders
-    // Maybe use a vecdeque we search on removal?
-    // Delay sorting
-    let mut price_to_order_queue: BTreeMap<u64, VecDeque<u64>> = BTreeMap::new();
+    let mut order_book = OrderBook::new();
 
     let mut state = State::ParseSign;
 
@@ -122,23 +103,11 @@ fn run_for_benchmark_by_char() {
                 state = State::ParseNumB;
             },
             (State::ParseNumA, NEWLINE, EQUALS) => {
-                // let start = Instant::now();
-
-                take_liquidity(&mut price_to_order_queue, num_a);
-
-                // let duration = start.elapsed();
-                // println!("Time elapsed in take_liquidity() is: {:?}", duration);
-
+                order_book.take(Side::Buy, num_a, TakeMode::Partial).unwrap();
                 state = State::ParseSign;
             },
             (State::ParseNumA, NEWLINE, MINUS) => {
-                // let start = Instant::now();
-
-                remove_order(&mut price_to_order_queue, num_a);
-
-                // let duration = start.elapsed();
-                // println!("Time elapsed in remove_order() is: {:?}", duration);
-
+                order_book.remove_at(Side::Sell, num_a).unwrap();
                 state = State::ParseSign;
             },
             (State::ParseNumA, NEWLINE, _) => (),
@@ -147,13 +116,7 @@ fn run_for_benchmark_by_char() {
                 num_a += (c as u64) - 0x30;
             },
             (State::ParseNumB, NEWLINE, _) => {
-                // let start = Instant::now();
-
-                add_liquidity(&mut price_to_order_queue, num_a, num_b);
-
-                // let duration = start.elapsed();
-                // println!("Time elapsed in add_liquidity() is: {:?}", duration);
-
+                order_book.add(Side::Sell, num_a, num_b).unwrap();
                 state = State::ParseSign;
             },
             (State::ParseNumB, _, _) => {
@@ -164,132 +127,51 @@ fn run_for_benchmark_by_char() {
     }
 }
 
-
-fn add_liquidity(
-    price_to_order_queue: &mut BTreeMap<u64, VecDeque<u64>>,
-    price: u64,
-    amount: u64
-) {
-    let order_queue =  price_to_order_queue.entry(price).or_insert(VecDeque::new());
-
-    // Currently wrapped Hashmap for queue
-    // Probably better to use something array based-ish
-    order_queue.push_back(amount);
-}
-
-fn remove_order(
-    price_to_order_queue: &mut BTreeMap<u64, VecDeque<u64>>,
-    index: u64
-) {
-
-    let mut order_queue_iter = price_to_order_queue.iter_mut();
-
-    let mut current_order_queue_entry = order_queue_iter.next().expect("No queue for price.");
-
-    let mut index_in_current_order_queue = index  as usize;
-
-    while index_in_current_order_queue >= current_order_queue_entry.1.len() {
-        index_in_current_order_queue -= current_order_queue_entry.1.len();
-
-        // We advance to next queue AFTER decrementing index with current queue length
-        current_order_queue_entry = order_queue_iter.next().expect("No queue for price.");
-    }
-    current_order_queue_entry.1.remove(index_in_current_order_queue);
-
-    // println!("AFTER REMOVAL current_price_queue: {:#?}", current_order_queue_entry.1);
-
-    // let mut empty = current_order_queue_entry.1.is_empty();
-    let empty_price = *current_order_queue_entry.0;
-
-    if current_order_queue_entry.1.is_empty() {
-        price_to_order_queue.remove(&empty_price);
-    }
-}
-
-
-// Best to know the total liquidity for each level
-// Allows use to reduce iteration
-// Otherwise iterate along range
-
-// Return cost
-fn take_liquidity(
-    price_to_order_queue: &mut BTreeMap<u64, VecDeque<u64>>,
-    amount: u64
-) -> u64 {
-    let mut remaining_amount = amount;
-    let mut cost = 0;
-
-    while remaining_amount > 0 {
-        let mut first_order_queue_entry = price_to_order_queue.first_entry().expect("No queue at first price.");
-        let first_order_queue_price = *first_order_queue_entry.key();
-        let first_order_queue = first_order_queue_entry.get_mut();
-
-        let mut front_order_amount_option = first_order_queue.front();
-        // let mut front_order_amount = ;
-        // println!("Outer loop first_order_queue.len(): {}", first_order_queue.len());
-
-        while front_order_amount_option.is_some() && remaining_amount >= *front_order_amount_option.unwrap() {
-            // println!("Inner loop first_order_queue.len(): {}", first_order_queue.len());
-            // println!("Inner loop front_order_amount: {}", front_order_amount_option.unwrap());
-            // println!("Inner loop pre remaining amount: {}", remaining_amount);
-            remaining_amount -= *front_order_amount_option.unwrap();
-
-            cost += first_order_queue_price * (*front_order_amount_option.unwrap());
-
-            // println!("Inner loop post remaining amount: {}", remaining_amount);
-            first_order_queue.pop_front();
-
-            front_order_amount_option = first_order_queue.front();
-        }
-
-        // If the queue is empty, remove price level - next iteration will advance
-        // Else update the front_order
-        if front_order_amount_option.is_some() && remaining_amount < *front_order_amount_option.unwrap() {
-            *first_order_queue.front_mut().unwrap() -= remaining_amount;
-            cost += first_order_queue_price * remaining_amount;
-            remaining_amount = 0;
-        } else {
-            price_to_order_queue.remove(&first_order_queue_price);
-        }
-
-    }
-
-    cost
-
-}
-
 #[link(name = "c")]
-extern {
+extern "C" {
     fn mmap(addr: *mut u8, len: usize, prot: i32, flags: i32, fd: i32, offset: i64) -> *mut u8;
-    // fn __errno_location() -> *const i32;
-    fn __error() -> *const i32; // Mac
     fn lseek(fd: i32, offset: i64, whence: i32) -> i64;
     fn open(path: *const u8, oflag: i32) -> i32;
 }
 
-#[allow(dead_code)]
-unsafe fn mmap_stdin<'a>() -> &'a [u8] {
+// errno lives behind __errno_location on Linux and __error on Mac, let std pick
+fn os_error(call: &'static str) -> Error {
+    Error::Os {
+        call,
+        errno: std::io::Error::last_os_error().raw_os_error().unwrap_or(0),
+    }
+}
+
+/// Maps all of stdin into memory.
+///
+/// # Safety
+///
+/// Stdin must be a regular file that nobody truncates while the slice lives.
+pub unsafe fn mmap_stdin<'a>() -> Result<&'a [u8]> {
     mmap_fd(0)
 }
 
-#[allow(dead_code)]
-unsafe fn mmap_path<'a>(path: &str) -> &'a [u8] {
+/// Maps the file at `path` into memory.
+///
+/// # Safety
+///
+/// Nobody may truncate the file while the slice lives.
+pub unsafe fn mmap_path<'a>(path: &str) -> Result<&'a [u8]> {
     let mut path2 = vec![];
     path2.extend_from_slice(path.as_bytes());
     path2.push(0);
     let fd = open(path2.as_ptr(), 0);
     if fd == -1 {
-        // panic!("open failed, errno {}", *__errno_location());
-        panic!("open failed, errno {}", *__error());    // Mac
+        return Err(os_error("open"));
     }
     mmap_fd(fd)
 }
 
-unsafe fn mmap_fd<'a>(fd: i32) -> &'a [u8] {
+unsafe fn mmap_fd<'a>(fd: i32) -> Result<&'a [u8]> {
     let seek_end = 2;
     let size = lseek(fd, 0, seek_end);
     if size == -1 {
-        panic!("lseek failed, errno {}", *__error());
+        return Err(os_error("lseek"));
     }
     let prot_read = 0x01;
     let map_private = 0x02;
@@ -297,11 +179,11 @@ unsafe fn mmap_fd<'a>(fd: i32) -> &'a [u8] {
     // let map_anon = 0x1000;  // Mac, don't want this. Give us anonymous memory not associated w/ any FD.
     // let map_populate = 0x08000; // Not available on Mac
     // let ptr = mmap(0 as _, size as usize, prot_read, map_private | map_populate, fd, 0);
-    let ptr = mmap(0 as _, size as usize, prot_read, map_private, fd, 0);
+    let ptr = mmap(std::ptr::null_mut(), size as usize, prot_read, map_private, fd, 0);
     if ptr as isize == -1 {
-        panic!("mmap failed, errno {}", *__error());
+        return Err(os_error("mmap"));
     }
-    std::slice::from_raw_parts(ptr, size as usize)
+    Ok(std::slice::from_raw_parts(ptr, size as usize))
 }
 
 extern crate test;
@@ -311,13 +193,40 @@ mod tests {
     use super::*;
     use test::Bencher;
 
+    #[test]
+    fn test_run_by_line_at_intervals() {
+        let mut order_book = OrderBook::new();
+        let mut snapshots = Vec::new();
+
+        run_by_line_at_intervals(&mut order_book, b"+ 10 5\nb 9 2\n= 3\n+ 11 1\n= 1\n", 2, |applied_count, order_book| {
+            snapshots.push((applied_count, order_book.l2_snapshot(1).to_string()));
+        })
+        .unwrap();
+
+        assert_eq!(vec![(2, "bid 9 2 1\nask 10 5 1".to_string()), (4, "bid 9 2 1\nask 10 2 1".to_string())], snapshots);
+    }
+
     #[bench]
     fn bench_run_by_line(b: &mut Bencher) {
-        b.iter(|| run_for_benchmark_by_line());
+        b.iter(run_for_benchmark_by_line);
     }
 
     #[bench]
     fn bench_run_by_char(b: &mut Bencher) {
-        b.iter(|| run_for_benchmark_by_char());
+        b.iter(run_for_benchmark_by_char);
+    }
+
+    #[bench]
+    fn bench_remove_at_many_levels(b: &mut Bencher) {
+        let mut order_book = OrderBook::new();
+        for price in 0..50_000 {
+            order_book.add(Side::Sell, price, 1).unwrap();
+        }
+
+        // Index 40_000 is the only order at price 40_000, put it straight back
+        b.iter(|| {
+            let order = order_book.remove_at(Side::Sell, 40_000).unwrap();
+            order_book.add(Side::Sell, 40_000, order.amount).unwrap();
+        });
     }
 }
\ No newline at end of file
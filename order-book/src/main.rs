@@ -1,229 +1,49 @@
-use std::collections::{BTreeMap, VecDeque};
-use std::time::Instant;
-
-const PLUS: u8 = 0x2b;
-const MINUS: u8 = 0x2d;
-const EQUALS: u8 = 0x3d;
-const SPACE: u8 = 0x20;
-const NEWLINE: u8 = 0x0a;
-
-enum State {
-    ParseSign,
-    ParseNumA,
-    ParseNumB
-}
-
-fn main() {
-    let buf = unsafe { mmap_stdin() };
-
-    let buf_len = buf.len();
-    // BTreeMap of an LinkedList (IndexMap does not allow for the same size value!)
-    // Price -> Sorted Orders
-
-    // Maybe use a vecdeque we search on removal?
-    // Delay sorting
-    let mut price_to_order_queue: BTreeMap<u64, VecDeque<u64>> = BTreeMap::new();
-
-    let mut sign: u8 = 0;
-    let mut num_a: u64 = 0;
-    let mut num_b: u64= 0;
-
-    // matching every character, state
-    // takes too long to long to parse the integers
-    // we can parse line by line very quickly
-    // we need to keep a pointer of sorts tho
-    // also we can disqualify if num_b == 0 since that's a no op
-    // so for each integer, iterate and build it up BUT
-    // we also check every stage that the char is in our desired range 0x30 to 0x39
-    // we break if it isn't
-
-    let mut i = 0;
-
-    while i < buf_len {
-        // println!("i: {}", i);
-        
-        sign = buf[i];
-        i += 2;
-        num_a = 0;
-        while buf[i] > 0x29 && buf[i] < 0x40 {
-            num_a *= 10;
-            num_a += (buf[i] - 0x30) as u64;
-            i += 1;
-        }
-
-        // println!("1 buf[i]: {:x}", buf[i]);
-        
-        if buf[i] == SPACE {
-            i += 1;
-            num_b = 0;
-            while buf[i] > 0x29 && buf[i] < 0x40 {
-                num_b *= 10;
-                num_b += (buf[i] - 0x30) as u64;
-                i += 1;
-            }
-        }
-
-        // println!("2 buf[i]: {:x}", buf[i]);
-        // Past next newline
-        i += 1;
-
-        // println!("{} {} {}", sign as char, num_a, num_b);
-
-        match sign {
-            PLUS => {
-                add_liquidity(&mut price_to_order_queue, num_a, num_b);
-            },
-            MINUS => {
-                remove_order(&mut price_to_order_queue, num_a);
-            },
-            EQUALS => {
-                take_liquidity(&mut price_to_order_queue, num_a);
-            },
-            _ => ()
+use order_book::{mmap_stdin, Journal, OrderBook, Parser, Result, Side};
+
+// Levels per side in the snapshots `order-book N` prints every N commands
+const SNAPSHOT_DEPTH: usize = 10;
+
+// `order-book [N] [--journal PATH]` replays stdin, printing a snapshot every N
+// commands and, with a journal, recovering the book from it first and
+// appending every command to it
+fn main() -> Result<()> {
+    let buf = unsafe { mmap_stdin()? };
+
+    let mut snapshot_interval = None;
+    let mut journal_path = None;
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        if arg == "--journal" {
+            journal_path = args.next();
+        } else {
+            snapshot_interval = arg.parse::<usize>().ok().filter(|&n| n > 0);
         }
     }
 
+    let mut order_book = OrderBook::new();
+    let mut journal = journal_path.map(|path| Journal::open(path, &mut order_book)).transpose()?;
 
-    // println!("{}", take_liquidity(&mut price_to_order_queue, 200));
-
-    println!("{}", take_liquidity(&mut price_to_order_queue, 1000));
-}
-
-fn add_liquidity(
-    price_to_order_queue: &mut BTreeMap<u64, VecDeque<u64>>,
-    price: u64,
-    amount: u64
-) {
-    let order_queue =  price_to_order_queue.entry(price).or_insert(VecDeque::new());
-
-    // Currently wrapped Hashmap for queue
-    // Probably better to use something array based-ish
-    order_queue.push_back(amount);
-}
-
-fn remove_order(
-    price_to_order_queue: &mut BTreeMap<u64, VecDeque<u64>>,
-    index: u64
-) {
-
-    let mut order_queue_iter = price_to_order_queue.iter_mut();
-
-    let mut current_order_queue_entry = order_queue_iter.next().expect("No queue for price.");
-
-    let mut index_in_current_order_queue = index  as usize;
-
-    while index_in_current_order_queue >= current_order_queue_entry.1.len() {
-        index_in_current_order_queue -= current_order_queue_entry.1.len();
-
-        // We advance to next queue AFTER decrementing index with current queue length
-        current_order_queue_entry = order_queue_iter.next().expect("No queue for price.");
-    }
-    current_order_queue_entry.1.remove(index_in_current_order_queue);
-
-    // println!("AFTER REMOVAL current_price_queue: {:#?}", current_order_queue_entry.1);
-
-    // let mut empty = current_order_queue_entry.1.is_empty();
-    let empty_price = *current_order_queue_entry.0;
-
-    if current_order_queue_entry.1.is_empty() {
-        price_to_order_queue.remove(&empty_price);
-    }
-}
-
-
-// Best to know the total liquidity for each level
-// Allows use to reduce iteration
-// Otherwise iterate along range
-
-// Return cost
-fn take_liquidity(
-    price_to_order_queue: &mut BTreeMap<u64, VecDeque<u64>>,
-    amount: u64
-) -> u64 {
-    let mut remaining_amount = amount;
-    let mut cost = 0;
-
-    while remaining_amount > 0 {
-        let mut first_order_queue_entry = price_to_order_queue.first_entry().expect("No queue at first price.");
-        let first_order_queue_price = *first_order_queue_entry.key();
-        let first_order_queue = first_order_queue_entry.get_mut();
-
-        let mut front_order_amount_option = first_order_queue.front();
-        // let mut front_order_amount = ;
-        // println!("Outer loop first_order_queue.len(): {}", first_order_queue.len());
-
-        while front_order_amount_option.is_some() && remaining_amount >= *front_order_amount_option.unwrap() {
-            // println!("Inner loop first_order_queue.len(): {}", first_order_queue.len());
-            // println!("Inner loop front_order_amount: {}", front_order_amount_option.unwrap());
-            // println!("Inner loop pre remaining amount: {}", remaining_amount);
-            remaining_amount -= *front_order_amount_option.unwrap();
-
-            cost += first_order_queue_price * (*front_order_amount_option.unwrap());
+    for (index, command) in Parser::new(buf).enumerate() {
+        let applied = command.and_then(|command| match journal.as_mut() {
+            Some(journal) => journal.apply(&mut order_book, command),
+            None => order_book.apply(command),
+        });
 
-            // println!("Inner loop post remaining amount: {}", remaining_amount);
-            first_order_queue.pop_front();
-
-            front_order_amount_option = first_order_queue.front();
+        // Reject the bad line and carry on with the rest
+        if let Err(error) = applied {
+            eprintln!("{}", error);
         }
 
-        // If the queue is empty, remove price level - next iteration will advance
-        // Else update the front_order
-        if front_order_amount_option.is_some() && remaining_amount < *front_order_amount_option.unwrap() {
-            *first_order_queue.front_mut().unwrap() -= remaining_amount;
-            cost += first_order_queue_price * remaining_amount;
-            remaining_amount = 0;
-        } else {
-            price_to_order_queue.remove(&first_order_queue_price);
+        if snapshot_interval.is_some_and(|interval| (index + 1) % interval == 0) {
+            println!("{}\n", order_book.l2_snapshot(SNAPSHOT_DEPTH));
         }
-
     }
 
-    cost
-
-}
-
-#[link(name = "c")]
-extern {
-    fn mmap(addr: *mut u8, len: usize, prot: i32, flags: i32, fd: i32, offset: i64) -> *mut u8;
-    // fn __errno_location() -> *const i32;
-    fn __error() -> *const i32; // Mac
-    fn lseek(fd: i32, offset: i64, whence: i32) -> i64;
-    fn open(path: *const u8, oflag: i32) -> i32;
-}
+    if let Some(journal) = &journal {
+        journal.sync()?;
+    }
 
-#[allow(dead_code)]
-unsafe fn mmap_stdin<'a>() -> &'a [u8] {
-    mmap_fd(0)
-}
+    println!("{}", order_book.quote(Side::Buy, 1000).cost);
 
-#[allow(dead_code)]
-unsafe fn mmap_path<'a>(path: &str) -> &'a [u8] {
-    let mut path2 = vec![];
-    path2.extend_from_slice(path.as_bytes());
-    path2.push(0);
-    let fd = open(path2.as_ptr(), 0);
-    if fd == -1 {
-        // panic!("open failed, errno {}", *__errno_location());
-        panic!("open failed, errno {}", *__error());    // Mac
-    }
-    mmap_fd(fd)
+    Ok(())
 }
-
-unsafe fn mmap_fd<'a>(fd: i32) -> &'a [u8] {
-    let seek_end = 2;
-    let size = lseek(fd, 0, seek_end);
-    if size == -1 {
-        panic!("lseek failed, errno {}", *__error());
-    }
-    let prot_read = 0x01;
-    let map_private = 0x02;
-    // https://stackoverflow.com/questions/44615134/what-is-wrong-with-mmap-system-call-on-mac-os-x
-    // let map_anon = 0x1000;  // Mac, don't want this. Give us anonymous memory not associated w/ any FD.
-    // let map_populate = 0x08000; // Not available on Mac
-    // let ptr = mmap(0 as _, size as usize, prot_read, map_private | map_populate, fd, 0);
-    let ptr = mmap(0 as _, size as usize, prot_read, map_private, fd, 0);
-    if ptr as isize == -1 {
-        panic!("mmap failed, errno {}", *__error());
-    }
-    std::slice::from_raw_parts(ptr, size as usize)
-}
\ No newline at end of file
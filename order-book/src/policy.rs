@@ -0,0 +1,163 @@
+use crate::book::Level;
+
+/// Decides how an incoming order shares its amount out among the orders of
+/// one price level when it cannot fill all of them.
+///
+/// The book only asks when `amount` is below `level.quantity()`, every policy
+/// fills every order once the amount covers the level.
+pub trait MatchingPolicy {
+    /// Pushes `(position in level, share)` onto `allocations` for every order
+    /// that gets a share, in queue order. Shares add up to `amount` and none
+    /// is above the amount the order shows.
+    fn allocate(&self, level: &Level, amount: u64, allocations: &mut Vec<(usize, u64)>);
+}
+
+/// Strict time priority, the front order fills before the next one gets any.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Fifo;
+
+/// Shares in proportion to the amount each order shows.
+///
+/// Every share is rounded down and shares below `min_allocation` are dropped.
+/// What rounding and dropping leave over goes out in time priority.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct ProRata {
+    pub min_allocation: u64,
+}
+
+/// The front order fills first, the rest is shared out like `ProRata` among
+/// the orders behind it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct TopOrderProRata {
+    pub min_allocation: u64,
+}
+
+impl MatchingPolicy for Fifo {
+    fn allocate(&self, level: &Level, mut amount: u64, allocations: &mut Vec<(usize, u64)>) {
+        for (index, order) in level.orders().enumerate() {
+            if amount == 0 {
+                break;
+            }
+
+            let share = order.amount.min(amount);
+            allocations.push((index, share));
+            amount -= share;
+        }
+    }
+}
+
+impl MatchingPolicy for ProRata {
+    fn allocate(&self, level: &Level, amount: u64, allocations: &mut Vec<(usize, u64)>) {
+        pro_rata(level, 0, level.quantity(), amount, self.min_allocation, allocations);
+    }
+}
+
+impl MatchingPolicy for TopOrderProRata {
+    fn allocate(&self, level: &Level, amount: u64, allocations: &mut Vec<(usize, u64)>) {
+        let Some(top_order) = level.orders().next() else {
+            return;
+        };
+
+        let top_share = top_order.amount.min(amount);
+        allocations.push((0, top_share));
+
+        let rest = level.quantity() - u128::from(top_order.amount);
+        pro_rata(level, 1, rest, amount - top_share, self.min_allocation, allocations);
+    }
+}
+
+// Shares `amount` out among the orders from position `skip` on, which show
+// `quantity` between them
+fn pro_rata(
+    level: &Level,
+    skip: usize,
+    quantity: u128,
+    amount: u64,
+    min_allocation: u64,
+    allocations: &mut Vec<(usize, u64)>
+) {
+    if amount == 0 {
+        return;
+    }
+
+    let start = allocations.len();
+    let mut left_over = amount;
+
+    for (index, order) in level.orders().enumerate().skip(skip) {
+        // u128 so amount * order.amount cannot overflow
+        let share = (amount as u128 * order.amount as u128 / quantity) as u64;
+        let share = if share < min_allocation { 0 } else { share };
+
+        allocations.push((index, share));
+        left_over -= share;
+    }
+
+    for (allocation, order) in allocations[start..].iter_mut().zip(level.orders().skip(skip)) {
+        if left_over == 0 {
+            break;
+        }
+
+        let top_up = (order.amount - allocation.1).min(left_over);
+        allocation.1 += top_up;
+        left_over -= top_up;
+    }
+
+    // Only orders that get something are reported
+    let mut kept = start;
+    for index in start..allocations.len() {
+        if allocations[index].1 > 0 {
+            allocations[kept] = allocations[index];
+            kept += 1;
+        }
+    }
+    allocations.truncate(kept);
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::book::{OrderBook, Side};
+
+    // Level at price 10 on the asks holding `amounts` in queue order
+    fn book_with_level(amounts: &[u64]) -> OrderBook {
+        let mut order_book = OrderBook::new();
+        for &amount in amounts {
+            order_book.add(Side::Sell, 10, amount).unwrap();
+        }
+
+        order_book
+    }
+
+    fn allocate(policy: impl MatchingPolicy, amounts: &[u64], amount: u64) -> Vec<(usize, u64)> {
+        let order_book = book_with_level(amounts);
+        let mut allocations = Vec::new();
+        policy.allocate(order_book.level(Side::Sell, 10).unwrap(), amount, &mut allocations);
+
+        assert_eq!(amount, allocations.iter().map(|&(_, share)| share).sum::<u64>());
+        allocations
+    }
+
+    #[test]
+    fn test_fifo() {
+        assert_eq!(vec![(0, 5), (1, 2)], allocate(Fifo, &[5, 5, 5], 7));
+    }
+
+    #[test]
+    fn test_pro_rata() {
+        // 10 of 40 is a quarter: 2.5, 5 and 2.5 round down to 2, 5 and 2, the
+        // unit left over goes to the front order
+        assert_eq!(vec![(0, 3), (1, 5), (2, 2)], allocate(ProRata::default(), &[10, 20, 10], 10));
+
+        // Shares of 1 are below the minimum and go to the front instead
+        assert_eq!(vec![(0, 2), (1, 4)], allocate(ProRata { min_allocation: 2 }, &[4, 16, 4], 6));
+    }
+
+    #[test]
+    fn test_top_order_pro_rata() {
+        // The top order takes 4, the other 8 splits evenly
+        assert_eq!(vec![(0, 4), (1, 4), (2, 4)], allocate(TopOrderProRata::default(), &[4, 10, 10], 12));
+
+        assert_eq!(vec![(0, 3)], allocate(TopOrderProRata::default(), &[4, 10, 10], 3));
+    }
+}
@@ -0,0 +1,162 @@
+use std::fmt;
+
+use crate::book::{OrderBook, OrderId, Side};
+use crate::clock::Clock;
+use crate::policy::MatchingPolicy;
+
+/// One price level added up, as a market-by-price snapshot shows it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LevelSnapshot {
+    pub price: u64,
+    /// Shown quantity, iceberg reserves left out
+    pub quantity: u128,
+    pub order_count: usize,
+}
+
+/// Market by price: the best levels on each side, best price first.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct L2Snapshot {
+    pub bids: Vec<LevelSnapshot>,
+    pub asks: Vec<LevelSnapshot>,
+}
+
+/// One resting order and where it queues, as a market-by-order snapshot
+/// shows it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OrderSnapshot {
+    pub id: OrderId,
+    pub price: u64,
+    /// Place in its level's queue, 0 at the front
+    pub position: usize,
+    pub amount: u64,
+    /// Iceberg reserve not yet shown
+    pub hidden: u64,
+}
+
+/// Market by order: every resting order on each side, best price first and
+/// in time priority within a price.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct L3Snapshot {
+    pub bids: Vec<OrderSnapshot>,
+    pub asks: Vec<OrderSnapshot>,
+}
+
+impl<P: MatchingPolicy, C: Clock> OrderBook<P, C> {
+    /// The best `depth` levels on each side, added up.
+    pub fn l2_snapshot(&self, depth: usize) -> L2Snapshot {
+        let side_snapshot = |side| {
+            self.levels(side)
+                .take(depth)
+                .map(|(price, level)| LevelSnapshot {
+                    price,
+                    quantity: level.quantity(),
+                    order_count: level.order_count(),
+                })
+                .collect()
+        };
+
+        L2Snapshot {
+            bids: side_snapshot(Side::Buy),
+            asks: side_snapshot(Side::Sell),
+        }
+    }
+
+    /// Every resting order with its place in the queue.
+    pub fn l3_snapshot(&self) -> L3Snapshot {
+        let side_snapshot = |side| {
+            self.levels(side)
+                .flat_map(|(price, level)| {
+                    level.orders().enumerate().map(move |(position, order)| OrderSnapshot {
+                        id: order.id,
+                        price,
+                        position,
+                        amount: order.amount,
+                        hidden: order.hidden(),
+                    })
+                })
+                .collect()
+        };
+
+        L3Snapshot {
+            bids: side_snapshot(Side::Buy),
+            asks: side_snapshot(Side::Sell),
+        }
+    }
+}
+
+// One line per entry, bids before asks, no newline after the last line
+fn write_lines<T>(
+    f: &mut fmt::Formatter<'_>,
+    bids: &[T],
+    asks: &[T],
+    write_line: impl Fn(&mut fmt::Formatter<'_>, &T) -> fmt::Result
+) -> fmt::Result {
+    let lines = bids.iter().map(|entry| ("bid", entry)).chain(asks.iter().map(|entry| ("ask", entry)));
+
+    for (index, (side, entry)) in lines.enumerate() {
+        if index > 0 {
+            writeln!(f)?;
+        }
+        write!(f, "{} ", side)?;
+        write_line(f, entry)?;
+    }
+
+    Ok(())
+}
+
+/// `bid price quantity order_count` or `ask ...` for each level.
+impl fmt::Display for L2Snapshot {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write_lines(f, &self.bids, &self.asks, |f, level| {
+            write!(f, "{} {} {}", level.price, level.quantity, level.order_count)
+        })
+    }
+}
+
+/// `bid price position id amount hidden` or `ask ...` for each order.
+impl fmt::Display for L3Snapshot {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write_lines(f, &self.bids, &self.asks, |f, order| {
+            write!(f, "{} {} {} {} {}", order.price, order.position, order.id, order.amount, order.hidden)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::book::NewOrder;
+
+    fn book() -> OrderBook {
+        let mut order_book = OrderBook::new();
+
+        order_book.add(Side::Buy, 9, 4).unwrap();
+        order_book.add(Side::Buy, 8, 1).unwrap();
+        order_book.add(Side::Buy, 9, 2).unwrap();
+        order_book.submit(NewOrder { peak: Some(1), ..NewOrder::limit(Side::Sell, 11, 5) }).unwrap();
+
+        order_book
+    }
+
+    #[test]
+    fn test_l2_snapshot() {
+        let snapshot = book().l2_snapshot(1);
+
+        assert_eq!(vec![LevelSnapshot { price: 9, quantity: 6, order_count: 2 }], snapshot.bids);
+        assert_eq!("bid 9 6 2\nask 11 1 1", snapshot.to_string());
+        assert_eq!("", L2Snapshot::default().to_string());
+    }
+
+    #[test]
+    fn test_l3_snapshot() {
+        let snapshot = book().l3_snapshot();
+
+        assert_eq!(3, snapshot.bids.len());
+        assert_eq!(
+            OrderSnapshot { id: 2, price: 9, position: 1, amount: 2, hidden: 0 },
+            snapshot.bids[1]
+        );
+        assert_eq!("bid 9 0 0 4 0\nbid 9 1 2 2 0\nbid 8 0 1 1 0\nask 11 0 3 1 4", snapshot.to_string());
+    }
+}